@@ -5,9 +5,14 @@ use rpcx::*;
 
 #[tokio::main]
 pub async fn main() -> Result<()> {
-    let mut c: Client = Client::new("127.0.0.1:8972");
-    c.start().map_err(|err| println!("{}", err)).unwrap();
-    c.opt.serialize_type = SerializeType::MsgPack;
+    let opt = Opt {
+        serialize_type: SerializeType::MsgPack,
+        ..Default::default()
+    };
+    let c = AsyncClient::connect("127.0.0.1:8972", opt)
+        .await
+        .map_err(|err| println!("{}", err))
+        .unwrap();
 
     let mut a = 1;
     loop {
@@ -17,7 +22,7 @@ pub async fn main() -> Result<()> {
         let args = ArithAddArgs { a, b: 10 };
         a += 1;
 
-        let resp = c
+        let reply: Result<ArithAddReply> = c
             .send(
                 &service_path,
                 &service_method,
@@ -27,8 +32,6 @@ pub async fn main() -> Result<()> {
                 &args,
             )
             .await;
-
-        let reply: Result<ArithAddReply> = get_result(resp, SerializeType::MsgPack);
         match reply {
             Ok(r) => println!("received: {:?}", r),
             Err(err) => println!("received err:{}", err),
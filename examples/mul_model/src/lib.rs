@@ -1,6 +1,3 @@
-use std::error::Error as StdError;
-
-use rmp_serde as rmps;
 use serde::{Deserialize, Serialize};
 
 use rpcx::*;
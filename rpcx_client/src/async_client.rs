@@ -0,0 +1,1014 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    time::{Duration, Instant},
+};
+
+use bytes::BytesMut;
+use futures::StreamExt;
+use rpcx_protocol::*;
+use tokio::{
+    io::{AsyncWriteExt, BufWriter},
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpStream,
+    },
+    runtime::Handle,
+    sync::{
+        mpsc::{self, UnboundedReceiver, UnboundedSender},
+        Notify, OwnedSemaphorePermit, Semaphore,
+    },
+    time,
+};
+use tokio_util::codec::FramedRead;
+
+use super::{Backpressure, CallOptions, Opt};
+
+/// the state of the connection of a client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// connecting, or negotiating the protocol again after reconnecting.
+    Connecting,
+    Ready,
+    /// waiting before the next attempt to reconnect.
+    Backoff,
+    /// closed by the client, or broken while `Opt::reconnect` is unset.
+    Closed,
+}
+
+/// a call waiting for its reply, holding its share of
+/// `Opt::max_pending_calls`.
+#[derive(Debug)]
+struct PendingCall {
+    sender: ReplySender,
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+/// a request waiting to be written, holding its share of
+/// `Opt::max_queued_bytes`.
+#[derive(Debug)]
+struct Queued {
+    data: Vec<u8>,
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl From<Vec<u8>> for Queued {
+    fn from(data: Vec<u8>) -> Self {
+        Queued {
+            data,
+            _permit: None,
+        }
+    }
+}
+
+/// the room a request takes until its reply arrives and until it is
+/// written.
+#[derive(Debug, Default)]
+struct Permits {
+    call: Option<OwnedSemaphorePermit>,
+    bytes: Option<OwnedSemaphorePermit>,
+}
+
+/// the limits of a connection, see `Opt::max_pending_calls` and
+/// `Opt::max_queued_bytes`. Heartbeats and cancellations bypass them.
+#[derive(Debug)]
+struct Limits {
+    calls: Option<Arc<Semaphore>>,
+    bytes: Option<Arc<Semaphore>>,
+    max_bytes: usize,
+}
+
+impl Limits {
+    fn new(opt: &Opt) -> Self {
+        // permits are acquired as u32
+        let max_bytes = opt.max_queued_bytes.min(u32::MAX as usize);
+        Limits {
+            calls: Some(opt.max_pending_calls)
+                .filter(|&max| max > 0)
+                .map(|max| Arc::new(Semaphore::new(max))),
+            bytes: Some(max_bytes)
+                .filter(|&max| max > 0)
+                .map(|max| Arc::new(Semaphore::new(max))),
+            max_bytes,
+        }
+    }
+
+    /// Returns the permits of `size` bytes, a request larger than the limit
+    /// takes all of them.
+    fn byte_permits(&self, size: usize) -> u32 {
+        size.min(self.max_bytes) as u32
+    }
+
+    /// Takes the room of a request of `size` bytes, and of its reply unless
+    /// it is oneway, if there is enough.
+    fn try_reserve(&self, size: usize, is_oneway: bool) -> Result<Permits> {
+        let mut permits = Permits::default();
+        if let (Some(calls), false) = (&self.calls, is_oneway) {
+            let permit = calls
+                .clone()
+                .try_acquire_owned()
+                .map_err(|_| Error::new(ErrorKind::ResourceExhausted, "too many pending calls"))?;
+            permits.call = Some(permit);
+        }
+        if let Some(ref bytes) = self.bytes {
+            let permit = bytes
+                .clone()
+                .try_acquire_many_owned(self.byte_permits(size))
+                .map_err(|_| Error::new(ErrorKind::ResourceExhausted, "too many queued bytes"))?;
+            permits.bytes = Some(permit);
+        }
+        Ok(permits)
+    }
+
+    /// Waits until there is room for a request, see `try_reserve`.
+    async fn reserve(&self, size: usize, is_oneway: bool) -> Permits {
+        let mut permits = Permits::default();
+        // the semaphores are never closed
+        if let (Some(calls), false) = (&self.calls, is_oneway) {
+            permits.call = calls.clone().acquire_owned().await.ok();
+        }
+        if let Some(ref bytes) = self.bytes {
+            permits.bytes = bytes
+                .clone()
+                .acquire_many_owned(self.byte_permits(size))
+                .await
+                .ok();
+        }
+        permits
+    }
+}
+
+/// the state a client shares with its calls and the task serving its
+/// connection.
+#[derive(Debug)]
+struct Shared {
+    seq: AtomicU64,
+    calls: Mutex<HashMap<u64, PendingCall>>,
+    limits: Limits,
+    state: Mutex<ConnectionState>,
+    /// the protocol agreed on the current connection.
+    protocol: RwLock<Handshake>,
+    /// the offer of the last handshake, repeated after reconnecting.
+    offer: Mutex<Option<Handshake>>,
+    closed: Notify,
+}
+
+impl Shared {
+    fn state(&self) -> ConnectionState {
+        *self.state.lock().unwrap()
+    }
+
+    /// Changes the state, unless the client was closed.
+    fn set_state(&self, state: ConnectionState) {
+        let mut current = self.state.lock().unwrap();
+        if *current != ConnectionState::Closed {
+            *current = state;
+        }
+    }
+
+    fn next_seq(&self) -> u64 {
+        self.seq.fetch_add(1, Ordering::SeqCst)
+    }
+
+    /// Returns the future of the reply to the heartbeat with `seq`, which is
+    /// sent while connecting as well, unlike calls, see `dispatch`.
+    fn register(&self, seq: u64) -> CallFuture {
+        let (sender, call_future) = CallFuture::pending();
+        let call = PendingCall {
+            sender,
+            _permit: None,
+        };
+        self.calls.lock().unwrap().insert(seq, call);
+        call_future
+    }
+
+    fn fail_call<E: ToString>(&self, seq: u64, err: &E) {
+        if let Some(call) = self.calls.lock().unwrap().remove(&seq) {
+            let _ = call
+                .sender
+                .send(Err(Error::new(ErrorKind::Network, err.to_string())));
+        }
+    }
+
+    /// Fails all pending calls, after the connection broke.
+    fn fail_calls<E: ToString>(&self, err: &E) {
+        let mut m = self.calls.lock().unwrap();
+        for (_, call) in m.drain() {
+            let _ = call
+                .sender
+                .send(Err(Error::new(ErrorKind::Network, err.to_string())));
+        }
+    }
+}
+
+/// a client to connect rpcx services from async code.
+///
+/// The connection is served by a task on the tokio runtime `connect` is
+/// called on, so no threads or runtimes are created. When the connection
+/// breaks, pending calls fail with `ErrorKind::Network` and the client
+/// reconnects in the background, see `Opt::reconnect`. The calls and bytes
+/// in flight can be limited, see `Opt::backpressure`.
+#[derive(Debug)]
+pub struct AsyncClient {
+    pub opt: Opt,
+    shared: Arc<Shared>,
+    sender: UnboundedSender<Queued>,
+    protocol: Handshake,
+    /// the runtime serving the connection.
+    runtime: Handle,
+}
+
+impl AsyncClient {
+    /// Connects to `addr` and starts serving the connection on the current
+    /// runtime.
+    pub async fn connect(addr: &str, opt: Opt) -> Result<AsyncClient> {
+        let stream = connect_stream(addr, &opt).await?;
+
+        let shared = Arc::new(Shared {
+            seq: AtomicU64::new(0),
+            calls: Mutex::new(HashMap::new()),
+            limits: Limits::new(&opt),
+            state: Mutex::new(ConnectionState::Ready),
+            protocol: RwLock::new(Handshake::default()),
+            offer: Mutex::new(None),
+            closed: Notify::new(),
+        });
+        let (sender, receiver) = mpsc::unbounded_channel();
+        tokio::spawn(run(
+            addr.to_owned(),
+            opt.clone(),
+            shared.clone(),
+            sender.clone(),
+            receiver,
+            stream,
+        ));
+
+        Ok(AsyncClient {
+            opt,
+            shared,
+            sender,
+            protocol: Handshake::default(),
+            runtime: Handle::current(),
+        })
+    }
+
+    /// Sends a request and returns a future of its reply, deserialized
+    /// with `opt.serialize_type`.
+    pub fn send<T>(
+        &self,
+        service_path: &str,
+        service_method: &str,
+        is_oneway: bool,
+        is_heartbeat: bool,
+        metadata: &Metadata,
+        args: &dyn RpcxParam,
+    ) -> TypedCallFuture<T>
+    where
+        T: RpcxParam + Default,
+    {
+        self.send_with_options(
+            service_path,
+            service_method,
+            is_oneway,
+            is_heartbeat,
+            metadata,
+            args,
+            &CallOptions::default(),
+        )
+    }
+
+    /// Sends a request with options of its own, see `CallOptions`.
+    #[allow(clippy::too_many_arguments)]
+    pub fn send_with_options<T>(
+        &self,
+        service_path: &str,
+        service_method: &str,
+        is_oneway: bool,
+        is_heartbeat: bool,
+        metadata: &Metadata,
+        args: &dyn RpcxParam,
+        options: &CallOptions,
+    ) -> TypedCallFuture<T>
+    where
+        T: RpcxParam + Default,
+    {
+        let call = self.send_raw_with(
+            &self.opt,
+            options,
+            service_path,
+            service_method,
+            is_oneway,
+            is_heartbeat,
+            metadata,
+            args,
+        );
+        TypedCallFuture::new(call, options.serialize_type(&self.opt))
+    }
+
+    /// Sends a request and returns a future of its undecoded reply.
+    pub fn send_raw(
+        &self,
+        service_path: &str,
+        service_method: &str,
+        is_oneway: bool,
+        is_heartbeat: bool,
+        metadata: &Metadata,
+        args: &dyn RpcxParam,
+    ) -> CallFuture {
+        self.send_raw_with(
+            &self.opt,
+            &CallOptions::default(),
+            service_path,
+            service_method,
+            is_oneway,
+            is_heartbeat,
+            metadata,
+            args,
+        )
+    }
+
+    // requests waiting for room are started by a future yielding their call
+    #[allow(clippy::too_many_arguments, clippy::async_yields_async)]
+    pub(crate) fn send_raw_with(
+        &self,
+        opt: &Opt,
+        options: &CallOptions,
+        service_path: &str,
+        service_method: &str,
+        is_oneway: bool,
+        is_heartbeat: bool,
+        metadata: &Metadata,
+        args: &dyn RpcxParam,
+    ) -> CallFuture {
+        if self.shared.state() != ConnectionState::Ready {
+            return CallFuture::ready(Err(Error::new(
+                ErrorKind::Network,
+                "client is not connected",
+            )));
+        }
+        let mut metadata = metadata.clone();
+        metadata.extend(options.metadata.clone());
+        if let Some(deadline) = options.deadline {
+            if deadline <= Instant::now() {
+                return CallFuture::ready(Err(Error::new(
+                    ErrorKind::Timeout,
+                    "call deadline exceeded",
+                )));
+            }
+            metadata.set_deadline(deadline);
+        }
+        let serialize_type = options.serialize_type(opt);
+        let protocol = self.shared.protocol.read().unwrap().clone();
+        let seq = self.shared.next_seq();
+
+        let req = args.into_bytes(serialize_type).and_then(|payload| {
+            Message::builder()
+                .version(protocol.version)
+                .message_type(MessageType::Request)
+                .oneway(is_oneway)
+                .heartbeat(is_heartbeat)
+                .serialize_type(serialize_type)
+                .compress_type(options.compress_type.unwrap_or(opt.compress_type))
+                .seq(seq)
+                .service_path(service_path)
+                .service_method(service_method)
+                .metadata(metadata)
+                .payload(payload)
+                .build()
+        });
+        let mut req = match req {
+            Ok(req) => req,
+            Err(err) => return CallFuture::ready(Err(err)),
+        };
+        req.apply_compress_threshold(opt.compress_threshold);
+        if let Some(encryption) = opt.encryption.get(service_path) {
+            if let Err(err) = encryption.seal(&mut req) {
+                return CallFuture::ready(Err(err));
+            }
+        }
+
+        let fragment_size = if protocol.has_feature(FRAGMENT_FEATURE) {
+            opt.fragment_size
+        } else {
+            0
+        };
+        let data: Vec<Vec<u8>> = split(req, fragment_size)
+            .into_iter()
+            .map(|fragment| fragment.encode())
+            .collect();
+        let size = data.iter().map(Vec::len).sum();
+        let notification =
+            if !is_oneway && opt.notify_cancel && protocol.has_feature(CANCEL_FEATURE) {
+                self.cancel_message(opt, &protocol, seq, service_path, service_method)
+                    .ok()
+            } else {
+                None
+            };
+
+        let call_future = match self.shared.limits.try_reserve(size, is_oneway) {
+            Ok(permits) => dispatch(
+                &self.shared,
+                &self.sender,
+                seq,
+                data,
+                permits,
+                is_oneway,
+                notification,
+            ),
+            Err(err) if opt.backpressure == Backpressure::Fail => {
+                return CallFuture::ready(Err(err))
+            }
+            Err(_) => {
+                let shared = self.shared.clone();
+                let sender = self.sender.clone();
+                CallFuture::deferred(async move {
+                    let permits = shared.limits.reserve(size, is_oneway).await;
+                    dispatch(
+                        &shared,
+                        &sender,
+                        seq,
+                        data,
+                        permits,
+                        is_oneway,
+                        notification,
+                    )
+                })
+            }
+        };
+
+        match options.deadline {
+            Some(deadline) if !is_oneway => {
+                // the timer has to be created on the runtime of the
+                // connection, calls of `Client` are sent from outside
+                let _guard = self.runtime.enter();
+                call_future.with_timer(time::sleep_until(deadline.into()))
+            }
+            _ => call_future,
+        }
+    }
+
+    /// Calls a method and waits for its reply, `None` for oneway calls.
+    ///
+    /// The returned future doesn't borrow the client or the arguments, so
+    /// it can be spawned.
+    pub fn call<T>(
+        &self,
+        service_path: &str,
+        service_method: &str,
+        is_oneway: bool,
+        metadata: &Metadata,
+        args: &dyn RpcxParam,
+    ) -> impl Future<Output = Option<Result<T>>>
+    where
+        T: RpcxParam + Default,
+    {
+        self.call_with_options(
+            service_path,
+            service_method,
+            is_oneway,
+            metadata,
+            args,
+            &CallOptions::default(),
+        )
+    }
+
+    /// Calls a method with options of its own, see `CallOptions`.
+    pub fn call_with_options<T>(
+        &self,
+        service_path: &str,
+        service_method: &str,
+        is_oneway: bool,
+        metadata: &Metadata,
+        args: &dyn RpcxParam,
+        options: &CallOptions,
+    ) -> impl Future<Output = Option<Result<T>>>
+    where
+        T: RpcxParam + Default,
+    {
+        let call = self.send_with_options::<T>(
+            service_path,
+            service_method,
+            is_oneway,
+            false,
+            metadata,
+            args,
+            options,
+        );
+        async move {
+            if is_oneway {
+                // the request may still wait for room to be sent
+                let _ = call.into_raw().await;
+                return None;
+            }
+            Some(call.await)
+        }
+    }
+
+    /// Negotiates the protocol version and features with the server.
+    ///
+    /// The offer is sent in the metadata of a heartbeat, so it should be the
+    /// first heartbeat after `connect`. It is sent again after reconnecting.
+    /// Servers which don't negotiate, like Go rpcx, are treated as version 0
+    /// without features.
+    pub async fn handshake(&mut self, offer: &Handshake) -> Result<&Handshake> {
+        let mut metadata = Metadata::new();
+        offer.write_offer(&mut metadata);
+
+        let reply = self
+            .send_raw("", "", false, true, &metadata, &BytesMut::new())
+            .await;
+        let metadata = match reply {
+            Ok(reply) => reply.metadata,
+            Err(err) if err.kind() == ErrorKind::Network => return Err(err),
+            Err(_) => Metadata::new(),
+        };
+        self.protocol = Handshake::read_accept(&metadata);
+        *self.shared.protocol.write().unwrap() = self.protocol.clone();
+        *self.shared.offer.lock().unwrap() = Some(offer.clone());
+        Ok(&self.protocol)
+    }
+
+    /// Returns the protocol version and features agreed with the server.
+    pub fn protocol(&self) -> &Handshake {
+        &self.protocol
+    }
+
+    /// Returns the number of calls waiting for their replies.
+    pub fn pending_calls(&self) -> usize {
+        self.shared.calls.lock().unwrap().len()
+    }
+
+    pub fn state(&self) -> ConnectionState {
+        self.shared.state()
+    }
+
+    /// Closes the connection and fails the pending calls. The client doesn't
+    /// reconnect afterwards, it is closed as well when it is dropped.
+    pub fn close(&self) {
+        *self.shared.state.lock().unwrap() = ConnectionState::Closed;
+        self.shared.closed.notify_one();
+    }
+
+    /// Returns a oneway request which tells the server that the call with
+    /// `seq` was canceled.
+    fn cancel_message(
+        &self,
+        opt: &Opt,
+        protocol: &Handshake,
+        seq: u64,
+        service_path: &str,
+        service_method: &str,
+    ) -> Result<Message> {
+        let mut msg = Message::new();
+        msg.set_version(protocol.version);
+        msg.set_message_type(MessageType::Request);
+        msg.set_oneway(true);
+        msg.set_serialize_type(opt.serialize_type)?;
+        msg.set_seq(self.shared.next_seq());
+        msg.service_path = service_path.to_owned();
+        msg.service_method = service_method.to_owned();
+        msg.metadata.insert(CANCEL_KEY.to_owned(), seq.to_string());
+        Ok(msg)
+    }
+}
+
+/// Registers a request which got room and queues it for writing.
+///
+/// The state is checked under the lock of the calls, and `run` changes it
+/// before failing the calls, so a call is either rejected here or failed
+/// with its connection, it can't be left waiting for a lost request.
+fn dispatch(
+    shared: &Arc<Shared>,
+    sender: &UnboundedSender<Queued>,
+    seq: u64,
+    data: Vec<Vec<u8>>,
+    permits: Permits,
+    is_oneway: bool,
+    notification: Option<Message>,
+) -> CallFuture {
+    let mut calls = shared.calls.lock().unwrap();
+    if shared.state() != ConnectionState::Ready {
+        return CallFuture::ready(Err(Error::new(
+            ErrorKind::Network,
+            "client is not connected",
+        )));
+    }
+
+    let call_future = if !is_oneway {
+        let (reply_sender, call_future) = CallFuture::pending();
+        calls.insert(
+            seq,
+            PendingCall {
+                sender: reply_sender,
+                _permit: permits.call,
+            },
+        );
+        let shared = shared.clone();
+        let sender = sender.clone();
+        call_future.on_cancel(move || {
+            // nothing to do once the reply arrived or the call failed
+            if shared.calls.lock().unwrap().remove(&seq).is_none() {
+                return;
+            }
+            if let Some(msg) = notification {
+                let _ = sender.send(msg.encode().into());
+            }
+        })
+    } else {
+        CallFuture::oneway()
+    };
+
+    // fragments are queued one by one, so other calls can go in between,
+    // and the room of the bytes is given back with the last one
+    let mut bytes = permits.bytes;
+    let count = data.len();
+    for (i, data) in data.into_iter().enumerate() {
+        let queued = Queued {
+            data,
+            _permit: if i + 1 == count { bytes.take() } else { None },
+        };
+        if let Err(err) = sender.send(queued) {
+            if let Some(call) = calls.remove(&seq) {
+                let _ = call
+                    .sender
+                    .send(Err(Error::new(ErrorKind::Network, err.to_string())));
+            }
+            break;
+        }
+    }
+
+    call_future
+}
+
+impl Drop for AsyncClient {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+async fn connect_stream(addr: &str, opt: &Opt) -> Result<TcpStream> {
+    let stream = if opt.connect_timeout.as_millis() == 0 {
+        TcpStream::connect(addr).await?
+    } else {
+        time::timeout(opt.connect_timeout, TcpStream::connect(addr))
+            .await
+            .map_err(|err| Error::new(ErrorKind::Timeout, err))??
+    };
+
+    if let Some(nodelay) = opt.nodelay {
+        stream.set_nodelay(nodelay)?;
+    }
+    if let Some(ttl) = opt.ttl {
+        stream.set_ttl(ttl)?;
+    }
+    Ok(stream)
+}
+
+/// Serves connections to `addr`, reconnecting with backoff after one broke,
+/// until the client is closed.
+async fn run(
+    addr: String,
+    opt: Opt,
+    shared: Arc<Shared>,
+    sender: UnboundedSender<Queued>,
+    mut receiver: UnboundedReceiver<Queued>,
+    stream: TcpStream,
+) {
+    let mut stream = Some(stream);
+    let mut attempt: u32 = 0;
+    loop {
+        if let Some(stream) = stream.take() {
+            let err = serve(stream, &opt, &shared, &sender, &mut receiver).await;
+            // no calls are registered once the state isn't ready
+            shared.set_state(ConnectionState::Backoff);
+            shared.fail_calls(&format!("connection lost: {}", err));
+        }
+        if !opt.reconnect || shared.state() == ConnectionState::Closed {
+            break;
+        }
+
+        shared.set_state(ConnectionState::Backoff);
+        let delay = opt.backoff.delay(attempt);
+        attempt = attempt.saturating_add(1);
+        tokio::select! {
+            _ = time::sleep(delay) => {}
+            _ = shared.closed.notified() => break,
+        }
+
+        shared.set_state(ConnectionState::Connecting);
+        // requests queued for the broken connection were failed already, but
+        // fail whatever slipped in meanwhile once more
+        while receiver.try_recv().is_ok() {}
+        shared.fail_calls(&"connection lost");
+        tokio::select! {
+            rt = connect_stream(&addr, &opt) => {
+                if let Ok(s) = rt {
+                    stream = Some(s);
+                    attempt = 0;
+                }
+            }
+            _ = shared.closed.notified() => break,
+        }
+    }
+
+    *shared.state.lock().unwrap() = ConnectionState::Closed;
+    shared.fail_calls(&"client is closed");
+}
+
+/// Serves one connection until it breaks or the client is closed, and
+/// returns why it ended.
+async fn serve(
+    stream: TcpStream,
+    opt: &Opt,
+    shared: &Shared,
+    sender: &UnboundedSender<Queued>,
+    receiver: &mut UnboundedReceiver<Queued>,
+) -> Error {
+    let (read_half, write_half) = stream.into_split();
+    let reader = read_loop(
+        read_half,
+        shared,
+        &opt.encryption,
+        Reassembler::new(opt.max_reassembly_size),
+        opt.decode_limits,
+        opt.read_timeout,
+    );
+    let writer = write_loop(write_half, receiver, opt.write_timeout);
+    let ready = async {
+        negotiate(opt, shared, sender).await;
+        shared.set_state(ConnectionState::Ready);
+        keepalive(opt, shared, sender).await
+    };
+
+    tokio::select! {
+        err = reader => err,
+        err = writer => err,
+        err = ready => err,
+        _ = shared.closed.notified() => Error::new(ErrorKind::Network, "client is closed"),
+    }
+}
+
+/// Repeats the last handshake on a new connection, the server forgot what
+/// was agreed on the broken one.
+async fn negotiate(opt: &Opt, shared: &Shared, sender: &UnboundedSender<Queued>) {
+    let offer = match shared.offer.lock().unwrap().clone() {
+        Some(offer) => offer,
+        None => return,
+    };
+    *shared.protocol.write().unwrap() = Handshake::default();
+
+    let mut metadata = Metadata::new();
+    offer.write_offer(&mut metadata);
+    if let Ok(reply) = send_heartbeat(opt, shared, sender, metadata).await {
+        *shared.protocol.write().unwrap() = Handshake::read_accept(&reply.metadata);
+    }
+}
+
+/// Sends heartbeats while the connection is ready, returns once one isn't
+/// answered within `Opt::heartbeat_timeout`.
+async fn keepalive(opt: &Opt, shared: &Shared, sender: &UnboundedSender<Queued>) -> Error {
+    if opt.heartbeat_interval.as_millis() == 0 {
+        return futures::future::pending().await;
+    }
+    loop {
+        time::sleep(opt.heartbeat_interval).await;
+        let heartbeat = send_heartbeat(opt, shared, sender, Metadata::new());
+        // error replies come from a live server as well
+        if opt.heartbeat_timeout.as_millis() == 0 {
+            let _ = heartbeat.await;
+        } else if time::timeout(opt.heartbeat_timeout, heartbeat)
+            .await
+            .is_err()
+        {
+            return Error::new(ErrorKind::Timeout, "heartbeat timed out");
+        }
+    }
+}
+
+/// Sends a heartbeat on the connection and returns its reply.
+async fn send_heartbeat(
+    opt: &Opt,
+    shared: &Shared,
+    sender: &UnboundedSender<Queued>,
+    metadata: Metadata,
+) -> Result<Reply> {
+    let seq = shared.next_seq();
+    let version = shared.protocol.read().unwrap().version;
+    let req = Message::builder()
+        .version(version)
+        .message_type(MessageType::Request)
+        .heartbeat(true)
+        .serialize_type(opt.serialize_type)
+        .seq(seq)
+        .metadata(metadata)
+        .build()?;
+
+    let call = shared.register(seq);
+    if let Err(err) = sender.send(req.encode().into()) {
+        shared.fail_call(seq, &err);
+    }
+    call.await
+}
+
+/// Reads replies and completes their calls until the connection breaks.
+async fn read_loop(
+    read_half: OwnedReadHalf,
+    shared: &Shared,
+    encryption: &HashMap<String, Encryption>,
+    mut reassembler: Reassembler,
+    limits: DecodeLimits,
+    read_timeout: Duration,
+) -> Error {
+    let mut reader = FramedRead::new(read_half, LenientCodec::with_limits(limits));
+    loop {
+        let next = if read_timeout.as_millis() == 0 {
+            reader.next().await
+        } else {
+            match time::timeout(read_timeout, reader.next()).await {
+                Ok(next) => next,
+                Err(err) => Some(Err(Error::new(ErrorKind::Timeout, err))),
+            }
+        };
+        let msg = match next {
+            Some(Ok(msg)) => msg,
+            Some(Err(err)) => return err,
+            None => return Error::new(ErrorKind::Network, "connection closed by the server"),
+        };
+
+        // an invalid reply only fails its call, the next frame can be read
+        let (seq, reply) = match msg {
+            Ok(msg) => {
+                let seq = msg.get_seq();
+                match reassembler.push(msg) {
+                    Ok(None) => continue,
+                    Ok(Some(mut msg)) => {
+                        (seq, open_reply(encryption, &mut msg, &limits).map(|()| msg))
+                    }
+                    Err(err) => (seq, Err(err)),
+                }
+            }
+            Err((msg, err)) => (msg.get_seq(), Err(err)),
+        };
+        let call = shared.calls.lock().unwrap().remove(&seq);
+        if let Some(call) = call {
+            let reply = reply.and_then(|msg| {
+                if let Some(err) = msg.reply_error() {
+                    return Err(err);
+                }
+                Ok(Reply {
+                    data: msg.payload,
+                    metadata: msg.metadata,
+                })
+            });
+            // the caller may have dropped its future
+            let _ = call.sender.send(reply);
+        }
+    }
+}
+
+/// Writes queued requests, flushing once the queue is empty, until the
+/// connection breaks.
+async fn write_loop(
+    write_half: OwnedWriteHalf,
+    receiver: &mut UnboundedReceiver<Queued>,
+    write_timeout: Duration,
+) -> Error {
+    let mut writer = BufWriter::new(write_half);
+    while let Some(queued) = receiver.recv().await {
+        let write = async {
+            writer.write_all(&queued.data).await?;
+            while let Ok(queued) = receiver.try_recv() {
+                writer.write_all(&queued.data).await?;
+            }
+            writer.flush().await
+        };
+        let rt = if write_timeout.as_millis() == 0 {
+            write.await.map_err(Error::from)
+        } else {
+            match time::timeout(write_timeout, write).await {
+                Ok(rt) => rt.map_err(Error::from),
+                Err(err) => Err(Error::new(ErrorKind::Timeout, err)),
+            }
+        };
+        if let Err(err) = rt {
+            return err;
+        }
+    }
+    Error::new(ErrorKind::Network, "client is closed")
+}
+
+/// Decrypts a reply from a service with encryption.
+///
+/// Error replies which can't be authenticated, plain ones of services with
+/// encryption or sealed ones of services without, are accepted, but only
+/// their message is kept, since their kind, code and details could be forged.
+fn open_reply(
+    encryption: &HashMap<String, Encryption>,
+    msg: &mut Message,
+    limits: &DecodeLimits,
+) -> Result<()> {
+    let is_error = msg.get_message_status_type() == Some(MessageStatusType::Error);
+    match encryption.get(&msg.service_path) {
+        Some(encryption) if msg.is_encrypted() || !is_error => encryption.open(msg, limits),
+        None if !msg.is_encrypted() => Ok(()),
+        _ if is_error => {
+            for key in [
+                ERROR_KIND_KEY,
+                ERROR_CODE_KEY,
+                ERROR_DETAILS_KEY,
+                ENCRYPTION_KEY_ID,
+            ]
+            .iter()
+            {
+                msg.metadata.remove(*key);
+            }
+            Ok(())
+        }
+        _ => Err(Error::new(
+            ErrorKind::Client,
+            "encrypted reply from a service without encryption",
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn open_error_reply() {
+        let key = EncryptionKey::new("k1", Cipher::Aes256Gcm, [7u8; 32]);
+        let encryption = Encryption::new(Arc::new(StaticKeyProvider::new(key)));
+        let mut services = HashMap::new();
+        services.insert("Arith".to_owned(), encryption.clone());
+        let limits = DecodeLimits::default();
+
+        let error_reply = || {
+            let req = Message::builder()
+                .service_path("Arith")
+                .service_method("Mul")
+                .build()
+                .unwrap();
+            let mut reply = req.get_reply().unwrap();
+            let mut err = ServerError::new(7, "unavailable");
+            err.add_detail("Retry", &true).unwrap();
+            reply.set_reply_error(&Error::new(ErrorKind::Unavailable, err));
+            reply
+        };
+
+        // plain errors only keep their message
+        let mut plain = error_reply();
+        open_reply(&services, &mut plain, &limits).unwrap();
+        let err = plain.reply_error().unwrap();
+        assert_eq!(ErrorKind::Other, err.kind());
+        assert_eq!("unavailable", err.to_string());
+        assert_eq!(0, err.server_error().unwrap().code);
+
+        // sealed ones are trusted
+        let mut opened = error_reply();
+        encryption.seal(&mut opened).unwrap();
+        open_reply(&services, &mut opened, &limits).unwrap();
+        let err = opened.reply_error().unwrap();
+        assert_eq!(ErrorKind::Unavailable, err.kind());
+        assert_eq!(7, err.server_error().unwrap().code);
+
+        // unless the client has no key
+        let mut sealed = error_reply();
+        encryption.seal(&mut sealed).unwrap();
+        open_reply(&HashMap::new(), &mut sealed, &limits).unwrap();
+        assert_eq!(ErrorKind::Other, sealed.reply_error().unwrap().kind());
+        assert!(!sealed.is_encrypted());
+    }
+
+    #[test]
+    fn limits() {
+        let opt = Opt {
+            max_pending_calls: 1,
+            max_queued_bytes: 100,
+            ..Default::default()
+        };
+        let limits = Limits::new(&opt);
+
+        let permits = limits.try_reserve(60, false).unwrap();
+        let err = limits.try_reserve(10, false).unwrap_err();
+        assert_eq!(ErrorKind::ResourceExhausted, err.kind());
+        // oneway requests only take bytes
+        let err = limits.try_reserve(60, true).unwrap_err();
+        assert_eq!(ErrorKind::ResourceExhausted, err.kind());
+        assert!(limits.try_reserve(40, true).is_ok());
+        drop(permits);
+
+        // larger requests take all the bytes
+        let permits = limits.try_reserve(1000, true).unwrap();
+        assert!(limits.try_reserve(1, true).is_err());
+        drop(permits);
+
+        let limits = Limits::new(&Opt::default());
+        assert!(limits.calls.is_none() && limits.bytes.is_none());
+    }
+}
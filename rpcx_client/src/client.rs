@@ -1,31 +1,57 @@
 use std::{
-    cell::RefCell,
     collections::HashMap,
-    error::Error as StdError,
-    io::{BufReader, BufWriter, Write},
-    net::{Shutdown, SocketAddr, TcpStream},
-    sync::{
-        atomic::{AtomicU64, Ordering},
-        mpsc::{self, Receiver, SendError, Sender},
-        Arc, Mutex,
-    },
-    thread,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
-use rpcx_protocol::{call::*, *};
-use tokio::runtime::Runtime;
+use futures::executor::block_on;
+use rand::Rng;
+use rpcx_protocol::*;
+use tokio::runtime::{Builder, Runtime};
 
-#[derive(Debug, Copy, Clone)]
+use super::{AsyncClient, ConnectionState};
+
+#[derive(Debug, Clone)]
 pub struct Opt {
     pub retry: u8,
     pub compress_type: CompressType,
+    /// payloads shorter than this are sent uncompressed.
+    pub compress_threshold: usize,
     pub serialize_type: SerializeType,
     pub connect_timeout: Duration,
     pub read_timeout: Duration,
     pub write_timeout: Duration,
     pub nodelay: Option<bool>,
     pub ttl: Option<u32>,
+    /// the encryption of services whose payloads are encrypted, by service
+    /// path.
+    pub encryption: HashMap<String, Encryption>,
+    /// requests with larger payloads are split into fragments of this size
+    /// if the server agreed on `FRAGMENT_FEATURE`, 0 never splits them.
+    pub fragment_size: usize,
+    /// the limit of the headers and payloads of incomplete fragmented
+    /// replies, which are buffered whole until their last fragment. It is
+    /// `DEFAULT_MAX_REASSEMBLY_SIZE` unless raised for larger replies.
+    pub max_reassembly_size: usize,
+    /// the limits replies are decoded, decrypted and decompressed with.
+    pub decode_limits: DecodeLimits,
+    /// tells the server about canceled calls if it agreed on
+    /// `CANCEL_FEATURE`, so it can stop their handlers.
+    pub notify_cancel: bool,
+    /// reconnects in the background after the connection broke.
+    pub reconnect: bool,
+    /// the delays between attempts to reconnect.
+    pub backoff: Backoff,
+    /// sends a heartbeat after each interval, 0 sends none.
+    pub heartbeat_interval: Duration,
+    /// the connection is broken if a heartbeat isn't answered in time, which
+    /// detects half-open connections. 0 waits for the replies forever.
+    pub heartbeat_timeout: Duration,
+    /// the limit of calls waiting for their replies, 0 sets none.
+    pub max_pending_calls: usize,
+    /// the limit of bytes of requests waiting to be written, 0 sets none.
+    pub max_queued_bytes: usize,
+    /// what sending does while a limit is reached.
+    pub backpressure: Backpressure,
 }
 
 impl Default for Opt {
@@ -33,160 +59,162 @@ impl Default for Opt {
         Opt {
             retry: 3,
             compress_type: CompressType::CompressNone,
+            compress_threshold: 0,
             serialize_type: SerializeType::JSON,
             connect_timeout: Default::default(),
             read_timeout: Default::default(),
             write_timeout: Default::default(),
             nodelay: None,
             ttl: None,
+            encryption: HashMap::new(),
+            fragment_size: 0,
+            max_reassembly_size: DEFAULT_MAX_REASSEMBLY_SIZE,
+            decode_limits: Default::default(),
+            notify_cancel: false,
+            reconnect: true,
+            backoff: Default::default(),
+            heartbeat_interval: Default::default(),
+            heartbeat_timeout: Duration::from_secs(10),
+            max_pending_calls: 0,
+            max_queued_bytes: 0,
+            backpressure: Backpressure::Wait,
+        }
+    }
+}
+
+/// the options of a single call, those which are unset are taken from
+/// `Opt`.
+#[derive(Debug, Clone, Default)]
+pub struct CallOptions {
+    /// the call fails with `ErrorKind::Timeout` once it passes. The server is
+    /// sent the time left, and skips or drops the call once it passes.
+    pub deadline: Option<Instant>,
+    pub serialize_type: Option<SerializeType>,
+    pub compress_type: Option<CompressType>,
+    /// sent along with the metadata of the call, overriding its entries.
+    pub metadata: Metadata,
+}
+
+impl CallOptions {
+    /// Returns options whose deadline is `timeout` from now, none if that
+    /// is too far away to represent.
+    pub fn timeout(timeout: Duration) -> Self {
+        CallOptions {
+            deadline: Instant::now().checked_add(timeout),
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn serialize_type(&self, opt: &Opt) -> SerializeType {
+        self.serialize_type.unwrap_or(opt.serialize_type)
+    }
+}
+
+/// what sending a request does while `Opt::max_pending_calls` or
+/// `Opt::max_queued_bytes` is reached.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backpressure {
+    /// the request is sent once there is room, the returned future waits
+    /// for it, so it has to be polled.
+    Wait,
+    /// the call fails with `ErrorKind::ResourceExhausted` at once.
+    Fail,
+}
+
+/// exponential backoff with jitter between attempts to reconnect.
+#[derive(Debug, Clone, Copy)]
+pub struct Backoff {
+    /// the delay before the first attempt.
+    pub initial: Duration,
+    /// the limit the delay grows to.
+    pub max: Duration,
+    /// the factor the delay grows by after each failed attempt.
+    pub multiplier: f64,
+    /// the delay is randomly changed by up to this fraction, so clients
+    /// don't reconnect all at once after a server restarted.
+    pub jitter: f64,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff {
+            initial: Duration::from_millis(100),
+            max: Duration::from_secs(30),
+            multiplier: 2.0,
+            jitter: 0.2,
         }
     }
 }
 
-#[derive(Debug, Default)]
-struct RpcData {
-    seq: u64,
-    data: Vec<u8>,
+impl Backoff {
+    /// Returns the delay before attempt `attempt`, counting from 0.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let max = self.max.as_secs_f64();
+        // `powi` saturates to infinity, which is clamped to `max` below
+        let exp = attempt.min(i32::MAX as u32) as i32;
+        let delay = self.initial.as_secs_f64() * self.multiplier.powi(exp);
+        let delay = if delay < max { delay } else { max };
+        let jitter = if self.jitter > 0.0 {
+            rand::thread_rng().gen_range(-self.jitter..=self.jitter)
+        } else {
+            0.0
+        };
+        let delay = (delay * (1.0 + jitter)).max(0.0);
+        // a `max` close to the largest `Duration` may overflow with the jitter
+        if delay < u64::MAX as f64 {
+            Duration::from_secs_f64(delay)
+        } else {
+            self.max
+        }
+    }
 }
 
-/// a direct client to connect rpcx services.
+/// the protocol of clients which aren't connected yet.
+static NO_PROTOCOL: Handshake = Handshake {
+    version: 0,
+    features: Vec::new(),
+};
+
+/// a direct client to connect rpcx services from blocking code.
+///
+/// It wraps an `AsyncClient` served by a runtime of its own with one worker
+/// thread. Async code should use `AsyncClient`, which shares the caller's
+/// runtime, but the blocking calls don't panic inside a runtime either.
 #[derive(Debug)]
 pub struct Client {
     pub opt: Opt,
     addr: String,
-    stream: Option<TcpStream>,
-    seq: Arc<AtomicU64>,
-    chan_sender: Sender<RpcData>,
-    chan_receiver: Arc<Mutex<Receiver<RpcData>>>,
-    calls: Arc<Mutex<HashMap<u64, ArcCall>>>,
+    runtime: Option<Runtime>,
+    inner: Option<AsyncClient>,
 }
 
 impl Client {
     pub fn new(addr: &str) -> Client {
-        let (sender, receiver) = mpsc::channel();
-
         Client {
             opt: Default::default(),
             addr: String::from(addr),
-            stream: None,
-            seq: Arc::new(AtomicU64::new(0)),
-            chan_sender: sender,
-            chan_receiver: Arc::new(Mutex::new(receiver)),
-            calls: Arc::new(Mutex::new(HashMap::new())),
+            runtime: None,
+            inner: None,
         }
     }
-    pub fn start(&mut self) -> Result<()> {
-        let stream = if self.opt.connect_timeout.as_millis() == 0 {
-            TcpStream::connect(self.addr.as_str())?
-        } else {
-            let socket_addr: SocketAddr = self
-                .addr
-                .parse()
-                .map_err(|err| Error::new(ErrorKind::Network, err))?;
-            TcpStream::connect_timeout(&socket_addr, self.opt.connect_timeout)?
-        };
-
-        if self.opt.read_timeout.as_millis() > 0 {
-            stream.set_read_timeout(Some(self.opt.read_timeout))?;
-        }
-        if self.opt.write_timeout.as_millis() > 0 {
-            stream.set_write_timeout(Some(self.opt.write_timeout))?;
-        }
-
-        if self.opt.nodelay.is_some() {
-            stream.set_nodelay(self.opt.nodelay.unwrap())?;
-        }
-        if self.opt.ttl.is_some() {
-            stream.set_ttl(self.opt.ttl.unwrap())?;
-        }
-        let read_stream = stream.try_clone()?;
-        let write_stream = stream.try_clone()?;
-        self.stream = Some(stream);
-
-        let calls = self.calls.clone();
-        thread::spawn(move || {
-            let mut reader = BufReader::new(read_stream.try_clone().unwrap());
-
-            loop {
-                let mut msg = Message::new();
-                match msg.decode(&mut reader) {
-                    Ok(()) => {
-                        if let Some(call) = calls.lock().unwrap().remove(&msg.get_seq()) {
-                            let internal_call_cloned = call.clone();
-                            let mut internal_call_mutex = internal_call_cloned.lock().unwrap();
-                            let internal_call = internal_call_mutex.get_mut();
-                            internal_call.is_client_error = false;
-                            if let Some(MessageStatusType::Error) = msg.get_message_status_type() {
-                                internal_call.error =
-                                    msg.get_error().unwrap_or_else(|| "".to_owned());
-                            } else {
-                                internal_call.reply_data.extend_from_slice(&msg.payload);
-                            }
-
-                            let mut status = internal_call.state.lock().unwrap();
-                            status.ready = true;
-                            if let Some(ref task) = status.task {
-                                task.clone().wake()
-                            }
-                        }
-                    }
-                    Err(err) => {
-                        println!("failed to read: {}", err.to_string());
-                        Self::drain_calls(calls, err);
-                        match read_stream.shutdown(Shutdown::Both) {
-                            Ok(_) => {}
-                            Err(err) => eprintln!("failed to shutdown stream: {}", err),
-                        }
-                        return;
-                    }
-                }
-            }
-        });
-
-        let chan_receiver = self.chan_receiver.clone();
-        let send_calls = self.calls.clone();
-        thread::spawn(move || {
-            let mut writer = BufWriter::new(write_stream.try_clone().unwrap());
-            loop {
-                match chan_receiver.lock().unwrap().recv() {
-                    Err(_err) => {
-                        //eprintln!("failed to fetch RpcData: {}", err.to_string());
-                        write_stream.shutdown(Shutdown::Both).unwrap();
-                        return;
-                    }
-                    Ok(rpcdata) => {
-                        match writer.write_all(rpcdata.data.as_slice()) {
-                            Ok(()) => {
-                                //println!("wrote");
-                            }
-                            Err(err) => {
-                                //println!("failed to write: {}", err.to_string());
-                                Self::drain_calls(send_calls.clone(), err);
-                                write_stream.shutdown(Shutdown::Both).unwrap();
-                                return;
-                            }
-                        }
-
-                        match writer.flush() {
-                            Ok(()) => {
-                                //println!("flushed");
-                            }
-                            Err(err) => {
-                                //println!("failed to flush: {}", err.to_string());
-                                Self::drain_calls(send_calls.clone(), err);
-                                write_stream.shutdown(Shutdown::Both).unwrap();
-                                return;
-                            }
-                        }
-                    }
-                }
-            }
-        });
 
+    pub fn start(&mut self) -> Result<()> {
+        let runtime = Builder::new_multi_thread()
+            .worker_threads(1)
+            .enable_all()
+            .build()?;
+        let addr = self.addr.clone();
+        let opt = self.opt.clone();
+        let connect = runtime.spawn(async move { AsyncClient::connect(&addr, opt).await });
+        let inner = block_on(connect).map_err(|err| Error::new(ErrorKind::Client, err))??;
+        self.inner = Some(inner);
+        self.runtime = Some(runtime);
         Ok(())
     }
 
-    pub fn send(
+    /// Sends a request and returns a future of its reply, deserialized
+    /// with `opt.serialize_type`.
+    pub fn send<T>(
         &self,
         service_path: &str,
         service_method: &str,
@@ -194,146 +222,213 @@ impl Client {
         is_heartbeat: bool,
         metadata: &Metadata,
         args: &dyn RpcxParam,
-    ) -> CallFuture {
-        let seq = self.seq.clone().fetch_add(1, Ordering::SeqCst);
-
-        let mut req = Message::new();
-        req.set_version(0);
-        req.set_message_type(MessageType::Request);
-        req.set_serialize_type(self.opt.serialize_type);
-        req.set_compress_type(self.opt.compress_type);
-        req.set_seq(seq);
-        req.service_path = service_path.to_string();
-        req.service_method = service_method.to_string();
-
-        let mut new_metadata = HashMap::with_capacity(metadata.len());
-        for (k, v) in metadata {
-            new_metadata.insert(k.clone(), v.clone());
-        }
-        req.metadata.replace(new_metadata);
-        let payload = args.into_bytes(self.opt.serialize_type).unwrap();
-        req.payload = payload;
-
-        let data = req.encode();
-
-        let call_future = if !is_oneway && !is_heartbeat {
-            let callback = Call::new(seq);
-            let arc_call = Arc::new(Mutex::new(RefCell::from(callback)));
-            self.calls
-                .clone()
-                .lock()
-                .unwrap()
-                .insert(seq, arc_call.clone());
-
-            CallFuture::new(Some(arc_call))
-        } else {
-            CallFuture::new(None)
-        };
-
-        let send_data = RpcData { seq, data };
-        match self.chan_sender.clone().send(send_data) {
-            Ok(_) => {}
-            Err(err) => self.remove_call_with_senderr(err),
-        }
-
-        call_future
+    ) -> TypedCallFuture<T>
+    where
+        T: RpcxParam + Default,
+    {
+        self.send_with_options(
+            service_path,
+            service_method,
+            is_oneway,
+            is_heartbeat,
+            metadata,
+            args,
+            &CallOptions::default(),
+        )
     }
 
-    fn remove_call_with_senderr(&self, err: SendError<RpcData>) {
-        let seq = err.0.seq;
-        let calls = self.calls.clone();
-        let mut m = calls.lock().unwrap();
-        if let Some(call) = m.remove(&seq) {
-            let internal_call_cloned = call.clone();
-            let mut internal_call_mutex = internal_call_cloned.lock().unwrap();
-            let internal_call = internal_call_mutex.get_mut();
-            internal_call.error = String::from(err.description());
-            let mut status = internal_call.state.lock().unwrap();
-            status.ready = true;
-            if let Some(ref task) = status.task {
-                task.clone().wake()
-            }
-        }
+    /// Sends a request with options of its own, see `CallOptions`.
+    #[allow(clippy::too_many_arguments)]
+    pub fn send_with_options<T>(
+        &self,
+        service_path: &str,
+        service_method: &str,
+        is_oneway: bool,
+        is_heartbeat: bool,
+        metadata: &Metadata,
+        args: &dyn RpcxParam,
+        options: &CallOptions,
+    ) -> TypedCallFuture<T>
+    where
+        T: RpcxParam + Default,
+    {
+        let call = match self.inner {
+            Some(ref inner) => inner.send_raw_with(
+                &self.opt,
+                options,
+                service_path,
+                service_method,
+                is_oneway,
+                is_heartbeat,
+                metadata,
+                args,
+            ),
+            None => not_started(),
+        };
+        TypedCallFuture::new(call, options.serialize_type(&self.opt))
     }
 
-    fn drain_calls<T: StdError>(calls: Arc<Mutex<HashMap<u64, ArcCall>>>, err: T) {
-        let mut m = calls.lock().unwrap();
-        for (_, call) in m.drain().take(1) {
-            let internal_call_cloned = call.clone();
-            let mut internal_call_mutex = internal_call_cloned.lock().unwrap();
-            let internal_call = internal_call_mutex.get_mut();
-            internal_call.error = String::from(err.description());
-            let mut status = internal_call.state.lock().unwrap();
-            status.ready = true;
-            if let Some(ref task) = status.task {
-                task.clone().wake()
-            }
+    /// Sends a request and returns a future of its undecoded reply.
+    pub fn send_raw(
+        &self,
+        service_path: &str,
+        service_method: &str,
+        is_oneway: bool,
+        is_heartbeat: bool,
+        metadata: &Metadata,
+        args: &dyn RpcxParam,
+    ) -> CallFuture {
+        match self.inner {
+            Some(ref inner) => inner.send_raw_with(
+                &self.opt,
+                &CallOptions::default(),
+                service_path,
+                service_method,
+                is_oneway,
+                is_heartbeat,
+                metadata,
+                args,
+            ),
+            None => not_started(),
         }
     }
 
-    #[allow(dead_code)]
-    fn remove_call_with_err<T: StdError>(&mut self, seq: u64, err: T) {
-        let calls = self.calls.clone();
-        let m = calls.lock().unwrap();
-        if let Some(call) = m.get(&seq) {
-            let internal_call_cloned = call.clone();
-            let mut internal_call_mutex = internal_call_cloned.lock().unwrap();
-            let internal_call = internal_call_mutex.get_mut();
-            internal_call.error = String::from(err.description());
-            let mut status = internal_call.state.lock().unwrap();
-            status.ready = true;
-            if let Some(ref task) = status.task {
-                task.clone().wake()
-            }
-        }
+    pub fn call<T>(
+        &mut self,
+        service_path: &str,
+        service_method: &str,
+        is_oneway: bool,
+        metadata: &Metadata,
+        args: &dyn RpcxParam,
+    ) -> Option<Result<T>>
+    where
+        T: RpcxParam + Default,
+    {
+        self.call_with_options(
+            service_path,
+            service_method,
+            is_oneway,
+            metadata,
+            args,
+            &CallOptions::default(),
+        )
     }
 
-    pub fn call<T>(
+    /// Calls a method with options of its own, see `CallOptions`.
+    pub fn call_with_options<T>(
         &mut self,
         service_path: &str,
         service_method: &str,
         is_oneway: bool,
         metadata: &Metadata,
         args: &dyn RpcxParam,
+        options: &CallOptions,
     ) -> Option<Result<T>>
     where
         T: RpcxParam + Default,
     {
-        let rt = Runtime::new().unwrap();
-        let callfuture = rt.block_on(async {
-            let f = self.send(
-                service_path,
-                service_method,
-                is_oneway,
-                false,
-                metadata,
-                args,
-            );
-            f.await
-        });
+        let reply = block_on(self.send_with_options::<T>(
+            service_path,
+            service_method,
+            is_oneway,
+            false,
+            metadata,
+            args,
+            options,
+        ));
 
         if is_oneway {
             return None;
         }
+        Some(reply)
+    }
+
+    /// Negotiates the protocol version and features with the server, see
+    /// `AsyncClient::handshake`.
+    pub fn handshake(&mut self, offer: &Handshake) -> Result<&Handshake> {
+        match self.inner {
+            Some(ref mut inner) => block_on(inner.handshake(offer)),
+            None => Err(Error::new(ErrorKind::Client, "client is not started")),
+        }
+    }
+
+    /// Returns the protocol version and features agreed with the server.
+    pub fn protocol(&self) -> &Handshake {
+        match self.inner {
+            Some(ref inner) => inner.protocol(),
+            None => &NO_PROTOCOL,
+        }
+    }
+
+    /// Returns the number of calls waiting for their replies.
+    pub fn pending_calls(&self) -> usize {
+        self.inner.as_ref().map_or(0, AsyncClient::pending_calls)
+    }
+
+    /// Returns the state of the connection, `Closed` before `start`.
+    pub fn state(&self) -> ConnectionState {
+        self.inner
+            .as_ref()
+            .map_or(ConnectionState::Closed, AsyncClient::state)
+    }
+
+    /// Closes the connection, see `AsyncClient::close`.
+    pub fn close(&self) {
+        if let Some(ref inner) = self.inner {
+            inner.close();
+        }
+    }
+}
 
-        let arc_call_1 = callfuture.unwrap().clone();
-        let mut arc_call_2 = arc_call_1.lock().unwrap();
-        let arc_call_3 = arc_call_2.get_mut();
-        let reply_data = &arc_call_3.reply_data;
-
-        if !arc_call_3.error.is_empty() {
-            let err = &arc_call_3.error;
-            if arc_call_3.is_client_error {
-                return Some(Err(Error::new(ErrorKind::Client, String::from(err))));
-            } else {
-                return Some(Err(Error::from(String::from(err))));
-            }
+impl Drop for Client {
+    fn drop(&mut self) {
+        self.close();
+        // dropping a runtime blocks, which isn't allowed in async code
+        if let Some(runtime) = self.runtime.take() {
+            runtime.shutdown_background();
         }
+    }
+}
+
+fn not_started() -> CallFuture {
+    CallFuture::ready(Err(Error::new(ErrorKind::Client, "client is not started")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn call_options_timeout() {
+        assert!(CallOptions::timeout(Duration::from_secs(1))
+            .deadline
+            .is_some());
+        let max = Duration::new(u64::MAX, 999_999_999);
+        assert_eq!(None, CallOptions::timeout(max).deadline);
+    }
+
+    #[test]
+    fn backoff() {
+        let backoff = Backoff {
+            jitter: 0.0,
+            ..Default::default()
+        };
+        assert_eq!(Duration::from_millis(100), backoff.delay(0));
+        assert_eq!(Duration::from_millis(400), backoff.delay(2));
+        assert_eq!(Duration::from_secs(30), backoff.delay(20));
+        assert_eq!(Duration::from_secs(30), backoff.delay(1 << 31));
+        assert_eq!(Duration::from_secs(30), backoff.delay(u32::MAX));
+
+        let backoff = Backoff {
+            max: Duration::from_secs(u64::MAX),
+            ..Default::default()
+        };
+        assert!(backoff.delay(u32::MAX) >= Duration::from_secs(u64::MAX / 2));
 
-        let mut reply: T = Default::default();
-        match reply.from_slice(self.opt.serialize_type, &reply_data) {
-            Ok(()) => Some(Ok(reply)),
-            Err(err) => Some(Err(err)),
+        let backoff = Backoff::default();
+        for _ in 0..100 {
+            let delay = backoff.delay(1);
+            assert!(delay >= Duration::from_millis(160) && delay <= Duration::from_millis(240));
         }
     }
 }
@@ -1,8 +1,10 @@
+pub mod async_client;
 pub mod client;
 pub mod discovery;
 pub mod selector;
 pub mod xclient;
 
+pub use async_client::*;
 pub use client::*;
 pub use discovery::*;
 pub use selector::*;
@@ -10,7 +12,7 @@ pub use xclient::*;
 
 use async_trait::async_trait;
 
-use rpcx_protocol::{CallFuture, Metadata, Result, RpcxParam};
+use rpcx_protocol::{Metadata, Result, RpcxParam, TypedCallFuture};
 
 #[async_trait]
 pub trait RpcxClient {
@@ -30,7 +32,7 @@ pub trait RpcxClient {
         is_oneway: bool,
         metadata: &Metadata,
         args: &dyn RpcxParam,
-    ) -> CallFuture
+    ) -> TypedCallFuture<T>
     where
         T: RpcxParam + Default + Sync + Send + 'static;
 }
@@ -1,6 +1,5 @@
-use qstring::QString;
 use rand::{prelude::*, Rng};
-use rpcx_protocol::{RpcxParam, SerializeType};
+use rpcx_protocol::{RpcxParam, SerializeType, ServiceMeta};
 use std::{
     collections::HashMap,
     sync::{Arc, RwLock},
@@ -134,16 +133,8 @@ impl ClientSelector for WeightedSelector {
 
         servers.reset();
         for (k, v) in map.iter() {
-            let qs = QString::from(v.as_str());
-            if let Some(val) = qs.get("weight") {
-                if let Ok(w) = val.parse::<isize>() {
-                    servers.add(k.clone(), w);
-                } else {
-                    servers.add(k.clone(), 1);
-                }
-            } else {
-                servers.add(k.clone(), 1);
-            }
+            let weight = ServiceMeta::parse(v).weight().unwrap_or(1);
+            servers.add(k.clone(), weight);
         }
     }
 }
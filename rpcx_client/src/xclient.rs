@@ -9,11 +9,11 @@ use super::{
     RpcxClient,
 };
 
-use rpcx_protocol::{call::*, CallFuture, Error, ErrorKind, Metadata, Result, RpcxParam};
+use rpcx_protocol::{CallFuture, Error, ErrorKind, Metadata, Result, RpcxParam, TypedCallFuture};
 use std::{
     boxed::Box,
     cell::RefCell,
-    sync::{Arc, Mutex, RwLock, RwLockWriteGuard},
+    sync::{Arc, RwLock, RwLockWriteGuard},
 };
 use strum_macros::{Display, EnumIter, EnumString};
 
@@ -86,7 +86,7 @@ impl<S: ClientSelector> XClient<S> {
                         items.insert(0, "tcp");
                     }
                     let mut created_client = Client::new(&items[1]);
-                    created_client.opt = self.opt;
+                    created_client.opt = self.opt.clone();
                     match created_client.start() {
                         Ok(_) => {
                             clients_guard.insert(k.clone(), RefCell::new(created_client));
@@ -105,6 +105,16 @@ impl<S: ClientSelector> XClient<S> {
     }
 }
 
+/// Tells whether a call failed before it was sent, so sending it again can't
+/// run it twice. Calls which failed with `ErrorKind::Network` or
+/// `ErrorKind::Timeout` may have run already and aren't sent again.
+fn unsent(err: &Error) -> bool {
+    matches!(
+        err.kind(),
+        ErrorKind::Client | ErrorKind::Unavailable | ErrorKind::ResourceExhausted
+    )
+}
+
 impl<S: ClientSelector> RpcxClient for XClient<S> {
     fn call<T>(
         &mut self,
@@ -145,7 +155,7 @@ impl<S: ClientSelector> RpcxClient for XClient<S> {
 
         match rt {
             Err(rt_err) => {
-                if rt_err.kind() == ErrorKind::Client {
+                if unsent(&rt_err) {
                     match self.fail_mode {
                         FailMode::Failover => {
                             let mut retry = self.opt.retry;
@@ -171,7 +181,7 @@ impl<S: ClientSelector> RpcxClient for XClient<S> {
                                 if rt.is_ok() {
                                     return Some(rt);
                                 }
-                                if rt.unwrap_err().kind() == ErrorKind::Client {
+                                if unsent(&rt.unwrap_err()) {
                                     continue;
                                 }
                             }
@@ -192,7 +202,7 @@ impl<S: ClientSelector> RpcxClient for XClient<S> {
                                 if rt.is_ok() {
                                     return Some(rt);
                                 }
-                                if rt.unwrap_err().kind() == ErrorKind::Client {
+                                if unsent(&rt.unwrap_err()) {
                                     continue;
                                 }
                             }
@@ -213,7 +223,7 @@ impl<S: ClientSelector> RpcxClient for XClient<S> {
         is_oneway: bool,
         metadata: &Metadata,
         args: &dyn RpcxParam,
-    ) -> CallFuture
+    ) -> TypedCallFuture<T>
     where
         T: RpcxParam + Default + Sync + Send + 'static,
     {
@@ -221,38 +231,15 @@ impl<S: ClientSelector> RpcxClient for XClient<S> {
         // get a key from selector
         let k = self.selector.select(service_path, service_method, args);
         if k.is_empty() {
-            let callback = Call::new(0);
-            let arc_call = Arc::new(Mutex::new(RefCell::from(callback)));
-            let internal_call_cloned = arc_call.clone();
-            let mut internal_call_mutex = internal_call_cloned.lock().unwrap();
-            let internal_call = internal_call_mutex.get_mut();
-            internal_call.error = "server not found".to_owned();
-            let mut status = internal_call.state.lock().unwrap();
-            status.ready = true;
-            if let Some(ref task) = status.task {
-                task.clone().wake()
-            }
-
-            return CallFuture::new(Some(arc_call));
+            let err = Error::new(ErrorKind::Client, "server not found".to_owned());
+            return TypedCallFuture::new(CallFuture::ready(Err(err)), self.opt.serialize_type);
         }
 
         let mut clients_guard = self.clients.write().unwrap();
         let client = self.get_cached_client(&mut clients_guard, k.clone());
 
         if let Err(err) = client {
-            let callback = Call::new(0);
-            let arc_call = Arc::new(Mutex::new(RefCell::from(callback)));
-            let internal_call_cloned = arc_call.clone();
-            let mut internal_call_mutex = internal_call_cloned.lock().unwrap();
-            let internal_call = internal_call_mutex.get_mut();
-            internal_call.error = err.to_string();
-            let mut status = internal_call.state.lock().unwrap();
-            status.ready = true;
-            if let Some(ref task) = status.task {
-                task.clone().wake()
-            }
-
-            return CallFuture::new(Some(arc_call));
+            return TypedCallFuture::new(CallFuture::ready(Err(err)), self.opt.serialize_type);
         }
 
         // invoke this client
@@ -268,3 +255,17 @@ impl<S: ClientSelector> RpcxClient for XClient<S> {
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resend_unsent_calls_only() {
+        assert!(unsent(&Error::new(ErrorKind::Client, "no connection")));
+        assert!(unsent(&Error::from(ErrorKind::Unavailable)));
+        assert!(!unsent(&Error::from(ErrorKind::Network)));
+        assert!(!unsent(&Error::from(ErrorKind::Timeout)));
+        assert!(!unsent(&Error::from(ErrorKind::Server)));
+    }
+}
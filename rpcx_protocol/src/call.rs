@@ -1,17 +1,17 @@
 use crate::Result;
 
 use std::{
-    cell::RefCell,
-    fmt::Debug,
+    fmt::{self, Debug},
     future::Future,
+    marker::PhantomData,
     pin::Pin,
-    sync::{Arc, Mutex},
-    task::{Context, Poll, Waker},
+    task::{Context, Poll},
 };
 
-use crate::SerializeType;
+use crate::{ErrorKind, Metadata, SerializeType};
 
 use bytes::BytesMut;
+use futures::channel::oneshot::{self, Canceled};
 
 use super::Error;
 
@@ -31,90 +31,322 @@ impl RpcxParam for BytesMut {
     }
 }
 
-#[derive(Debug)]
-pub struct Status {
-    pub ready: bool,
-    pub task: Option<Waker>,
+/// the handshake feature of servers which stop the handlers of calls
+/// canceled by the client, see `CANCEL_KEY`.
+pub const CANCEL_FEATURE: &str = "cancel";
+
+/// a reply as received by the client, before it is deserialized.
+#[derive(Debug, Default)]
+pub struct Reply {
+    pub data: Vec<u8>,
+    pub metadata: Metadata,
 }
 
-#[derive(Debug)]
-pub struct Call {
-    pub seq: u64,
-    pub is_client_error: bool,
-    pub state: Arc<Mutex<Status>>,
-    pub error: String,
-    pub reply_data: Vec<u8>,
+/// the sending half of a call, completed by the client once the reply
+/// arrives or the call fails.
+pub type ReplySender = oneshot::Sender<Result<Reply>>;
+
+/// a future which resolves to the raw reply of a call.
+///
+/// Oneway calls have no reply and resolve to an empty one at once. Dropping
+/// the future before it resolved cancels the call, see `on_cancel`.
+pub struct CallFuture {
+    start: Option<Pin<Box<dyn Future<Output = CallFuture> + Send>>>,
+    receiver: Option<oneshot::Receiver<Result<Reply>>>,
+    canceller: Option<Box<dyn FnOnce() + Send>>,
+    timer: Option<Pin<Box<dyn Future<Output = ()> + Send>>>,
 }
 
-impl Call {
-    pub fn new(seq: u64) -> Self {
-        Call {
-            seq,
-            is_client_error: true,
-            state: Arc::new(Mutex::new(Status {
-                ready: false,
-                task: None,
-            })),
-            error: String::new(),
-            reply_data: Vec::new(),
-        }
+impl Debug for CallFuture {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CallFuture")
+            .field("started", &self.start.is_none())
+            .field("receiver", &self.receiver)
+            .field("cancelable", &self.canceller.is_some())
+            .field("timed", &self.timer.is_some())
+            .finish()
     }
 }
 
-pub type ArcCall = Arc<Mutex<RefCell<Call>>>;
+impl CallFuture {
+    /// Creates a pending call and the sender which completes it.
+    pub fn pending() -> (ReplySender, Self) {
+        let (sender, receiver) = oneshot::channel();
+        (
+            sender,
+            CallFuture {
+                start: None,
+                receiver: Some(receiver),
+                canceller: None,
+                timer: None,
+            },
+        )
+    }
 
-pub fn get_result<T>(arc_call: Option<ArcCall>, st: SerializeType) -> Result<T>
-where
-    T: RpcxParam + Default,
-{
-    if arc_call.is_none() {
-        return Err(Error::from("reply is empty"));
+    /// Creates a call which is already completed with `reply`.
+    pub fn ready(reply: Result<Reply>) -> Self {
+        let (sender, call) = Self::pending();
+        let _ = sender.send(reply);
+        call
     }
-    let arc_call_1 = arc_call.unwrap().clone();
-    let mut arc_call_2 = arc_call_1.lock().unwrap();
-    let arc_call_3 = arc_call_2.get_mut();
-    let reply_data = &arc_call_3.reply_data;
-    if !arc_call_3.error.is_empty() {
-        let err = &arc_call_3.error;
-        return Err(Error::from(String::from(err)));
+
+    /// Creates the call of a oneway request.
+    pub fn oneway() -> Self {
+        CallFuture {
+            start: None,
+            receiver: None,
+            canceller: None,
+            timer: None,
+        }
     }
 
-    let mut reply: T = Default::default();
-    match reply.from_slice(st, &reply_data) {
-        Ok(()) => Ok(reply),
-        Err(err) => Err(err),
+    /// Creates a call which is only started once `start` completes, after
+    /// it is polled. Nothing needs to be canceled if it is dropped before.
+    pub fn deferred<F>(start: F) -> Self
+    where
+        F: Future<Output = CallFuture> + Send + 'static,
+    {
+        CallFuture {
+            start: Some(Box::pin(start)),
+            receiver: None,
+            canceller: None,
+            timer: None,
+        }
+    }
+
+    /// Sets `f` to be run if the future is canceled or dropped before it
+    /// resolved, for the client to forget the call.
+    pub fn on_cancel<F>(mut self, f: F) -> Self
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.canceller = Some(Box::new(f));
+        self
+    }
+
+    /// Fails the call with `ErrorKind::Timeout` if `timer` completes before
+    /// the reply arrives, which cancels it like dropping the future.
+    pub fn with_timer<F>(mut self, timer: F) -> Self
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        self.timer = Some(Box::pin(timer));
+        self
+    }
+
+    /// Cancels the call, the same as dropping the future.
+    pub fn cancel(self) {
+        drop(self)
+    }
+
+    /// Fails and cancels the call once its timer expired.
+    fn poll_timer(&mut self, cx: &mut Context<'_>) -> Poll<Result<Reply>> {
+        let expired = match self.timer.as_mut() {
+            Some(timer) => timer.as_mut().poll(cx).is_ready(),
+            None => false,
+        };
+        if !expired {
+            return Poll::Pending;
+        }
+        self.timer = None;
+        if let Some(canceller) = self.canceller.take() {
+            canceller();
+        }
+        Poll::Ready(Err(Error::new(
+            ErrorKind::Timeout,
+            "call deadline exceeded",
+        )))
     }
-}
-pub struct CallFuture {
-    pub arc_call: Option<ArcCall>,
 }
 
-impl CallFuture {
-    pub fn new(opt: Option<ArcCall>) -> Self {
-        CallFuture { arc_call: opt }
+impl Drop for CallFuture {
+    fn drop(&mut self) {
+        if let Some(canceller) = self.canceller.take() {
+            canceller();
+        }
     }
 }
 
 impl Future for CallFuture {
-    type Output = Option<ArcCall>;
+    type Output = Result<Reply>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if let Some(start) = self.start.as_mut() {
+            let mut call = match start.as_mut().poll(cx) {
+                Poll::Ready(call) => call,
+                Poll::Pending => return self.poll_timer(cx),
+            };
+            self.start = None;
+            self.receiver = call.receiver.take();
+            self.canceller = call.canceller.take();
+            if self.timer.is_none() {
+                self.timer = call.timer.take();
+            }
+        }
+        let receiver = match self.receiver.as_mut() {
+            Some(receiver) => receiver,
+            None => return Poll::Ready(Ok(Reply::default())),
+        };
+        let rt = match Pin::new(receiver).poll(cx) {
+            Poll::Ready(Ok(reply)) => reply,
+            Poll::Ready(Err(Canceled)) => Err(Error::new(
+                ErrorKind::Canceled,
+                "call was dropped without a reply",
+            )),
+            Poll::Pending => return self.poll_timer(cx),
+        };
+        // a completed call is not canceled any more
+        self.canceller = None;
+        self.timer = None;
+        Poll::Ready(rt)
+    }
+}
 
-    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        if self.arc_call.is_none() {
-            return Poll::Ready(None);
+/// a future which resolves to the deserialized reply of a call.
+///
+/// Oneway calls resolve to `T::default()`.
+#[derive(Debug)]
+pub struct TypedCallFuture<T> {
+    call: CallFuture,
+    st: SerializeType,
+    reply: PhantomData<fn() -> T>,
+}
+
+impl<T> TypedCallFuture<T> {
+    /// Wraps `call`, whose reply is deserialized with `st`.
+    pub fn new(call: CallFuture, st: SerializeType) -> Self {
+        TypedCallFuture {
+            call,
+            st,
+            reply: PhantomData,
         }
+    }
+
+    /// Returns the untyped call, to read the reply metadata as well.
+    pub fn into_raw(self) -> CallFuture {
+        self.call
+    }
+
+    /// Cancels the call, the same as dropping the future.
+    pub fn cancel(self) {
+        self.call.cancel()
+    }
+}
+
+impl<T> Future for TypedCallFuture<T>
+where
+    T: RpcxParam + Default,
+{
+    type Output = Result<T>;
 
-        let arc_call = self.arc_call.as_ref().unwrap().clone();
-        let mut arc_call_1 = arc_call.lock().unwrap();
-        let state = &arc_call_1.get_mut().state;
-        let mut status = state.lock().expect("!lock");
-        if status.ready {
-            Poll::Ready(Some(arc_call.clone()))
-        } else {
-            status.task = Some(cx.waker().clone());
-            Poll::Pending
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let st = self.st;
+        match Pin::new(&mut self.call).poll(cx) {
+            Poll::Ready(Ok(reply)) => {
+                let mut rt: T = Default::default();
+                Poll::Ready(rt.from_slice(st, &reply.data).map(|()| rt))
+            }
+            Poll::Ready(Err(err)) => Poll::Ready(Err(err)),
+            Poll::Pending => Poll::Pending,
         }
     }
 }
 
-unsafe impl Send for Call {}
-unsafe impl Sync for Call {}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    #[test]
+    fn typed_call() {
+        let (sender, call) = CallFuture::pending();
+        let call = TypedCallFuture::<BytesMut>::new(call, SerializeType::JSON);
+        sender
+            .send(Ok(Reply {
+                data: b"{}".to_vec(),
+                metadata: Metadata::new(),
+            }))
+            .unwrap();
+        assert_eq!(&b"{}"[..], &block_on(call).unwrap()[..]);
+
+        let call = CallFuture::ready(Err(Error::new(ErrorKind::Server, "failed")));
+        let err =
+            block_on(TypedCallFuture::<BytesMut>::new(call, SerializeType::JSON)).unwrap_err();
+        assert_eq!(ErrorKind::Server, err.kind());
+
+        let call = TypedCallFuture::<BytesMut>::new(CallFuture::oneway(), SerializeType::JSON);
+        assert!(block_on(call).unwrap().is_empty());
+    }
+
+    #[test]
+    fn cancel_call() {
+        let canceled = Arc::new(AtomicUsize::new(0));
+
+        let (_sender, call) = CallFuture::pending();
+        let counter = canceled.clone();
+        let call = call.on_cancel(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+        TypedCallFuture::<BytesMut>::new(call, SerializeType::JSON).cancel();
+        assert_eq!(1, canceled.load(Ordering::SeqCst));
+
+        let (_sender, call) = CallFuture::pending();
+        let counter = canceled.clone();
+        drop(call.on_cancel(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+        }));
+        assert_eq!(2, canceled.load(Ordering::SeqCst));
+
+        // resolved calls are not canceled
+        let counter = canceled.clone();
+        let mut call = CallFuture::ready(Ok(Reply::default())).on_cancel(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+        block_on(&mut call).unwrap();
+        drop(call);
+        assert_eq!(2, canceled.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn timed_out_call() {
+        let canceled = Arc::new(AtomicUsize::new(0));
+
+        let (_sender, call) = CallFuture::pending();
+        let counter = canceled.clone();
+        let call = call
+            .on_cancel(move || {
+                counter.fetch_add(1, Ordering::SeqCst);
+            })
+            .with_timer(futures::future::ready(()));
+        assert_eq!(ErrorKind::Timeout, block_on(call).unwrap_err().kind());
+        assert_eq!(1, canceled.load(Ordering::SeqCst));
+
+        // replies win over expired timers
+        let call = CallFuture::ready(Ok(Reply::default())).with_timer(futures::future::ready(()));
+        assert!(block_on(call).is_ok());
+    }
+
+    #[test]
+    fn deferred_call() {
+        let (sender, call) = CallFuture::pending();
+        let call = CallFuture::deferred(futures::future::ready(call));
+        sender.send(Ok(Reply::default())).unwrap();
+        assert!(block_on(call).is_ok());
+
+        // waiting to start counts against the deadline
+        let call =
+            CallFuture::deferred(futures::future::pending()).with_timer(futures::future::ready(()));
+        assert_eq!(ErrorKind::Timeout, block_on(call).unwrap_err().kind());
+    }
+
+    #[test]
+    fn dropped_call() {
+        let (sender, call) = CallFuture::pending();
+        drop(sender);
+        assert_eq!(ErrorKind::Canceled, block_on(call).unwrap_err().kind());
+    }
+}
@@ -0,0 +1,304 @@
+use byteorder::{BigEndian, ByteOrder};
+use bytes::BytesMut;
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::{
+    message::{check_header, protocol_error},
+    DecodeLimits, Error, Frame, Message, Result,
+};
+
+/// length of the fixed header plus the u32 length prefix of the body.
+const FRAME_HEAD_LEN: usize = 12 + 4;
+
+/// at most this many bytes are reserved ahead for a partial frame, so a bogus
+/// length prefix can't make the codec allocate a huge buffer up front.
+const MAX_RESERVE: usize = 64 * 1024;
+
+/// a tokio codec which frames rpcx messages on any `AsyncRead`/`AsyncWrite`.
+///
+/// It can be used with `tokio_util::codec::Framed`, `FramedRead` and
+/// `FramedWrite`. Partial frames stay in the read buffer until the rest of the
+/// bytes arrive.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct RpcxCodec {
+    limits: DecodeLimits,
+}
+
+impl RpcxCodec {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn with_limits(limits: DecodeLimits) -> Self {
+        RpcxCodec { limits }
+    }
+}
+
+impl Decoder for RpcxCodec {
+    type Item = Message;
+    type Error = Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Message>> {
+        let frame = match split_frame(src, &self.limits)? {
+            Some(frame) => frame,
+            None => return Ok(None),
+        };
+
+        let mut msg = Message::new();
+        msg.header.copy_from_slice(&frame[..12]);
+        msg.decode_body(&frame[FRAME_HEAD_LEN..], &self.limits)?;
+        Ok(Some(msg))
+    }
+}
+
+/// a tokio codec like `RpcxCodec` which carries on after an invalid message.
+///
+/// A frame which is read whole but whose body is invalid, e.g. because its
+/// payload doesn't match its checksum, is yielded as `Err` with the message
+/// as far as it was decoded, whose header tells the call it belongs to. The
+/// frames after it can still be read. Errors of the framing end the stream.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct LenientCodec {
+    limits: DecodeLimits,
+}
+
+impl LenientCodec {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn with_limits(limits: DecodeLimits) -> Self {
+        LenientCodec { limits }
+    }
+}
+
+impl Decoder for LenientCodec {
+    type Item = std::result::Result<Message, (Message, Error)>;
+    type Error = Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>> {
+        let frame = match split_frame(src, &self.limits)? {
+            Some(frame) => frame,
+            None => return Ok(None),
+        };
+
+        let mut msg = Message::new();
+        msg.header.copy_from_slice(&frame[..12]);
+        match msg.decode_body(&frame[FRAME_HEAD_LEN..], &self.limits) {
+            Ok(()) => Ok(Some(Ok(msg))),
+            Err(err) => Ok(Some(Err((msg, err)))),
+        }
+    }
+}
+
+impl Encoder<Message> for RpcxCodec {
+    type Error = Error;
+
+    fn encode(&mut self, item: Message, dst: &mut BytesMut) -> Result<()> {
+        Encoder::<&Message>::encode(self, &item, dst)
+    }
+}
+
+impl Encoder<&Message> for RpcxCodec {
+    type Error = Error;
+
+    fn encode(&mut self, item: &Message, dst: &mut BytesMut) -> Result<()> {
+        item.encode_to(dst);
+        Ok(())
+    }
+}
+
+/// a tokio codec like `RpcxCodec` which yields zero-copy `Frame`s.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct FrameCodec {
+    limits: DecodeLimits,
+}
+
+impl FrameCodec {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn with_limits(limits: DecodeLimits) -> Self {
+        FrameCodec { limits }
+    }
+}
+
+impl Decoder for FrameCodec {
+    type Item = Frame;
+    type Error = Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Frame>> {
+        let mut frame = match split_frame(src, &self.limits)? {
+            Some(frame) => frame,
+            None => return Ok(None),
+        };
+
+        let mut header = [0u8; 12];
+        header.copy_from_slice(&frame[..12]);
+        let body = frame.split_off(FRAME_HEAD_LEN).freeze();
+        Frame::from_parts_with_limits(header, body, &self.limits).map(Some)
+    }
+}
+
+impl Encoder<Frame> for FrameCodec {
+    type Error = Error;
+
+    fn encode(&mut self, item: Frame, dst: &mut BytesMut) -> Result<()> {
+        item.encode_to(dst);
+        Ok(())
+    }
+}
+
+impl Encoder<&Frame> for FrameCodec {
+    type Error = Error;
+
+    fn encode(&mut self, item: &Frame, dst: &mut BytesMut) -> Result<()> {
+        item.encode_to(dst);
+        Ok(())
+    }
+}
+
+/// Splits one complete frame off the front of `src`, or reserves room for
+/// more of it and returns `None`.
+fn split_frame(src: &mut BytesMut, limits: &DecodeLimits) -> Result<Option<BytesMut>> {
+    check_header(&src[..src.len().min(12)])?;
+    if src.len() < FRAME_HEAD_LEN {
+        src.reserve(FRAME_HEAD_LEN - src.len());
+        return Ok(None);
+    }
+
+    let body_len = BigEndian::read_u32(&src[12..FRAME_HEAD_LEN]) as usize;
+    if body_len > limits.max_frame_size {
+        return Err(protocol_error("frame exceeds the size limit"));
+    }
+    let frame_len = FRAME_HEAD_LEN + body_len;
+    if src.len() < frame_len {
+        src.reserve((frame_len - src.len()).min(MAX_RESERVE));
+        return Ok(None);
+    }
+
+    Ok(Some(src.split_to(frame_len)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RpcxMessage;
+
+    const MSG_DATA: [u8; 114] = [
+        8, 0, 0, 16, 0, 0, 0, 0, 73, 150, 2, 210, 0, 0, 0, 98, 0, 0, 0, 5, 65, 114, 105, 116, 104,
+        0, 0, 0, 3, 65, 100, 100, 0, 0, 0, 48, 0, 0, 0, 4, 95, 95, 73, 68, 0, 0, 0, 36, 54, 98, 97,
+        55, 98, 56, 49, 48, 45, 57, 100, 97, 100, 45, 49, 49, 100, 49, 45, 56, 48, 98, 52, 45, 48,
+        48, 99, 48, 52, 102, 100, 52, 51, 48, 99, 57, 0, 0, 0, 26, 123, 10, 9, 9, 34, 65, 34, 58,
+        32, 49, 44, 10, 9, 9, 34, 66, 34, 58, 32, 50, 44, 10, 9, 125, 10, 9,
+    ];
+
+    #[test]
+    fn decode_partial_reads() {
+        let mut codec = RpcxCodec::new();
+        let mut buf = BytesMut::new();
+
+        // feed the frame a few bytes at a time, like short TCP reads do.
+        for chunk in MSG_DATA.chunks(7) {
+            assert!(codec.decode(&mut buf).unwrap().is_none());
+            buf.extend_from_slice(chunk);
+        }
+
+        let msg = codec.decode(&mut buf).unwrap().unwrap();
+        assert!(buf.is_empty());
+        assert_eq!("Arith", msg.service_path);
+        assert_eq!("Add", msg.service_method);
+        assert_eq!(1234567890, msg.get_seq());
+        assert_eq!(
+            "{\n\t\t\"A\": 1,\n\t\t\"B\": 2,\n\t}\n\t",
+            std::str::from_utf8(&msg.payload).unwrap()
+        );
+    }
+
+    #[test]
+    fn decode_back_to_back_frames() {
+        let mut codec = RpcxCodec::new();
+        let mut buf = BytesMut::new();
+        buf.extend_from_slice(&MSG_DATA);
+        buf.extend_from_slice(&MSG_DATA[..20]);
+
+        let msg = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!("Arith", msg.service_path);
+        assert_eq!(20, buf.len());
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+
+        buf.extend_from_slice(&MSG_DATA[20..]);
+        let msg = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!("Add", msg.service_method);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn encode_round_trip() {
+        let mut codec = RpcxCodec::new();
+        let mut buf = BytesMut::new();
+        buf.extend_from_slice(&MSG_DATA);
+        let msg = codec.decode(&mut buf).unwrap().unwrap();
+
+        codec.encode(msg, &mut buf).unwrap();
+        assert_eq!(&MSG_DATA[..], &buf[..]);
+    }
+
+    #[test]
+    fn reject_oversized_frames() {
+        let limits = DecodeLimits {
+            max_frame_size: 64,
+            ..Default::default()
+        };
+        let mut codec = RpcxCodec::with_limits(limits);
+        let mut buf = BytesMut::new();
+        buf.extend_from_slice(&MSG_DATA[..20]);
+
+        let err = codec.decode(&mut buf).unwrap_err();
+        assert_eq!(crate::ErrorKind::Protocol, err.kind());
+    }
+
+    #[test]
+    fn frame_codec_round_trip() {
+        let mut codec = FrameCodec::new();
+        let mut buf = BytesMut::new();
+        for chunk in MSG_DATA.chunks(50) {
+            assert!(codec.decode(&mut buf).unwrap().is_none());
+            buf.extend_from_slice(chunk);
+        }
+        let frame = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!("Arith", frame.service_path_str());
+
+        codec.encode(frame, &mut buf).unwrap();
+        assert_eq!(&MSG_DATA[..], &buf[..]);
+    }
+
+    #[test]
+    fn lenient_checksum_mismatch() {
+        let msg = crate::MessageBuilder::new()
+            .seq(7)
+            .service_path("Arith")
+            .service_method("Mul")
+            .checksum(true)
+            .payload(b"{\"A\":10,\"B\":20}".to_vec())
+            .build()
+            .unwrap();
+        let mut corrupt = msg.encode();
+        *corrupt.last_mut().unwrap() ^= 1;
+
+        let mut buf = BytesMut::new();
+        buf.extend_from_slice(&corrupt);
+        buf.extend_from_slice(&MSG_DATA);
+        assert!(RpcxCodec::new().decode(&mut buf.clone()).is_err());
+
+        // only the corrupt message is lost
+        let mut codec = LenientCodec::new();
+        let (bad, err) = codec.decode(&mut buf).unwrap().unwrap().unwrap_err();
+        assert_eq!(7, bad.get_seq());
+        assert_eq!(crate::ErrorKind::Protocol, err.kind());
+        let msg = codec.decode(&mut buf).unwrap().unwrap().unwrap();
+        assert_eq!(1234567890, msg.get_seq());
+        assert!(buf.is_empty());
+    }
+}
@@ -0,0 +1,293 @@
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use lazy_static::lazy_static;
+use num_traits::ToPrimitive;
+
+use std::{
+    collections::HashMap,
+    io::{Read, Write},
+    sync::{Arc, RwLock},
+};
+
+use crate::{CompressType, Error, ErrorKind, Result};
+
+/// compresses and decompresses payloads for one `CompressType`.
+///
+/// Implementations are registered with `register_compressor` and shared by
+/// all messages, so they must be thread-safe.
+pub trait Compressor: Send + Sync {
+    fn compress(&self, data: &[u8]) -> Result<Vec<u8>>;
+    /// Decompresses `data`. Implementations should stop and fail as soon as
+    /// the output grows beyond `max_len` bytes.
+    fn decompress(&self, data: &[u8], max_len: usize) -> Result<Vec<u8>>;
+}
+
+lazy_static! {
+    static ref COMPRESSORS: RwLock<HashMap<u8, Arc<dyn Compressor>>> = {
+        let mut m: HashMap<u8, Arc<dyn Compressor>> = HashMap::new();
+        m.insert(1, Arc::new(GzipCompressor::default()));
+        #[cfg(feature = "snappy")]
+        m.insert(2, Arc::new(SnappyCompressor));
+        #[cfg(feature = "zstd")]
+        m.insert(3, Arc::new(ZstdCompressor::default()));
+        #[cfg(feature = "lz4")]
+        m.insert(4, Arc::new(Lz4Compressor::default()));
+        RwLock::new(m)
+    };
+}
+
+/// Registers `c` for `ct`, replacing the compressor registered before.
+///
+/// Use it to plug in a compressor for a `CompressType::Custom` id, or to
+/// change the level of a built-in one, e.g.
+/// `register_compressor(CompressType::Gzip, Arc::new(GzipCompressor::new(9)))`.
+pub fn register_compressor(ct: CompressType, c: Arc<dyn Compressor>) -> Result<()> {
+    let id = compress_id(ct)?;
+    if id == 0 {
+        return Err(Error::new(
+            ErrorKind::Other,
+            "CompressNone can't have a compressor",
+        ));
+    }
+    COMPRESSORS.write().unwrap().insert(id, c);
+    Ok(())
+}
+
+/// Returns the compressor registered for `ct`.
+pub fn get_compressor(ct: CompressType) -> Option<Arc<dyn Compressor>> {
+    let id = compress_id(ct).ok()?;
+    COMPRESSORS.read().unwrap().get(&id).cloned()
+}
+
+fn compress_id(ct: CompressType) -> Result<u8> {
+    ct.to_u8()
+        .ok_or_else(|| Error::new(ErrorKind::Other, "compress ids must fit in 3 bits"))
+}
+
+fn limit_exceeded() -> Error {
+    Error::new(ErrorKind::Protocol, "payload exceeds the size limit")
+}
+
+/// Reads all of `r`, failing when it yields more than `max_len` bytes.
+fn read_limited<R: Read>(r: R, max_len: usize) -> Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    r.take(max_len as u64 + 1)
+        .read_to_end(&mut buf)
+        .map_err(|err| Error::new(ErrorKind::Protocol, err))?;
+    if buf.len() > max_len {
+        return Err(limit_exceeded());
+    }
+    Ok(buf)
+}
+
+/// gzip, which is what Go rpcx uses for `CompressType::Gzip`.
+#[derive(Debug, Clone, Copy)]
+pub struct GzipCompressor {
+    level: Compression,
+}
+
+impl GzipCompressor {
+    /// Creates a gzip compressor with a level from 0 (none) to 9 (best).
+    pub fn new(level: u32) -> Self {
+        GzipCompressor {
+            level: Compression::new(level),
+        }
+    }
+}
+
+impl Default for GzipCompressor {
+    fn default() -> Self {
+        GzipCompressor {
+            level: Compression::fast(),
+        }
+    }
+}
+
+impl Compressor for GzipCompressor {
+    fn compress(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let mut e = GzEncoder::new(Vec::new(), self.level);
+        e.write_all(data)?;
+        Ok(e.finish()?)
+    }
+    fn decompress(&self, data: &[u8], max_len: usize) -> Result<Vec<u8>> {
+        read_limited(GzDecoder::new(data), max_len)
+    }
+}
+
+/// snappy in its framing format, like Go rpcx writes it with
+/// `snappy.NewBufferedWriter`.
+#[cfg(feature = "snappy")]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SnappyCompressor;
+
+#[cfg(feature = "snappy")]
+impl Compressor for SnappyCompressor {
+    fn compress(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let mut e = snap::write::FrameEncoder::new(Vec::new());
+        e.write_all(data)?;
+        e.into_inner().map_err(|err| err.into_error().into())
+    }
+    fn decompress(&self, data: &[u8], max_len: usize) -> Result<Vec<u8>> {
+        read_limited(snap::read::FrameDecoder::new(data), max_len)
+    }
+}
+
+/// zstd frames.
+#[cfg(feature = "zstd")]
+#[derive(Debug, Clone, Copy)]
+pub struct ZstdCompressor {
+    level: i32,
+}
+
+#[cfg(feature = "zstd")]
+impl ZstdCompressor {
+    /// Creates a zstd compressor, 0 selects the default level of zstd.
+    pub fn new(level: i32) -> Self {
+        ZstdCompressor { level }
+    }
+}
+
+#[cfg(feature = "zstd")]
+impl Default for ZstdCompressor {
+    fn default() -> Self {
+        ZstdCompressor::new(0)
+    }
+}
+
+#[cfg(feature = "zstd")]
+impl Compressor for ZstdCompressor {
+    fn compress(&self, data: &[u8]) -> Result<Vec<u8>> {
+        Ok(zstd::stream::encode_all(data, self.level)?)
+    }
+    fn decompress(&self, data: &[u8], max_len: usize) -> Result<Vec<u8>> {
+        let decoder = zstd::stream::read::Decoder::new(data)
+            .map_err(|err| Error::new(ErrorKind::Protocol, err))?;
+        read_limited(decoder, max_len)
+    }
+}
+
+/// LZ4 frames.
+#[cfg(feature = "lz4")]
+#[derive(Debug, Clone, Copy)]
+pub struct Lz4Compressor {
+    level: u32,
+}
+
+#[cfg(feature = "lz4")]
+impl Lz4Compressor {
+    /// Creates a LZ4 compressor, 0 is the fast mode and higher levels select
+    /// LZ4 HC.
+    pub fn new(level: u32) -> Self {
+        Lz4Compressor { level }
+    }
+}
+
+#[cfg(feature = "lz4")]
+impl Default for Lz4Compressor {
+    fn default() -> Self {
+        Lz4Compressor::new(0)
+    }
+}
+
+#[cfg(feature = "lz4")]
+impl Compressor for Lz4Compressor {
+    fn compress(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let mut e = lz4::EncoderBuilder::new()
+            .level(self.level)
+            .build(Vec::new())?;
+        e.write_all(data)?;
+        let (buf, result) = e.finish();
+        result?;
+        Ok(buf)
+    }
+    fn decompress(&self, data: &[u8], max_len: usize) -> Result<Vec<u8>> {
+        let decoder =
+            lz4::Decoder::new(data).map_err(|err| Error::new(ErrorKind::Protocol, err))?;
+        read_limited(decoder, max_len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Message, RpcxMessage};
+
+    fn round_trip(ct: CompressType) {
+        let data = "rpcx".repeat(1000).into_bytes();
+        let c = get_compressor(ct).unwrap();
+        let compressed = c.compress(&data).unwrap();
+        assert!(compressed.len() < data.len());
+        assert_eq!(data, c.decompress(&compressed, data.len()).unwrap());
+
+        let err = c.decompress(&compressed, data.len() - 1).unwrap_err();
+        assert_eq!(ErrorKind::Protocol, err.kind());
+    }
+
+    #[test]
+    fn gzip() {
+        round_trip(CompressType::Gzip);
+    }
+
+    #[cfg(feature = "snappy")]
+    #[test]
+    fn snappy() {
+        round_trip(CompressType::Snappy);
+
+        // the stream identifier, then an uncompressed chunk with its masked
+        // CRC32C, as Go writes short payloads
+        let framed = [
+            0xff, 0x06, 0x00, 0x00, b's', b'N', b'a', b'P', b'p', b'Y', 0x01, 0x08, 0x00, 0x00,
+            0x94, 0xe1, 0x9d, 0xcc, b'r', b'p', b'c', b'x',
+        ];
+        let c = get_compressor(CompressType::Snappy).unwrap();
+        assert_eq!(&framed[..], &c.compress(b"rpcx").unwrap()[..]);
+        assert_eq!(b"rpcx", &c.decompress(&framed, 4).unwrap()[..]);
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn zstd() {
+        round_trip(CompressType::Zstd);
+    }
+
+    #[cfg(feature = "lz4")]
+    #[test]
+    fn lz4() {
+        round_trip(CompressType::Lz4);
+    }
+
+    /// reverses the payload, good enough to tell it was applied.
+    struct Reverse;
+
+    impl Compressor for Reverse {
+        fn compress(&self, data: &[u8]) -> Result<Vec<u8>> {
+            Ok(data.iter().rev().cloned().collect())
+        }
+        fn decompress(&self, data: &[u8], _max_len: usize) -> Result<Vec<u8>> {
+            self.compress(data)
+        }
+    }
+
+    #[test]
+    fn custom_compressor() {
+        let ct = CompressType::Custom(7);
+        assert!(get_compressor(ct).is_none());
+        register_compressor(ct, Arc::new(Reverse)).unwrap();
+
+        let mut msg = Message::new();
+        msg.set_compress_type(ct).unwrap();
+        msg.payload = b"abc".to_vec();
+        let data = msg.encode();
+        assert_eq!(b"cba", &data[data.len() - 3..]);
+
+        let mut decoded = Message::new();
+        decoded.decode(&mut &data[..]).unwrap();
+        assert_eq!(Some(ct), decoded.get_compress_type());
+        assert_eq!(b"abc".to_vec(), decoded.payload);
+    }
+
+    #[test]
+    fn invalid_ids() {
+        assert!(register_compressor(CompressType::CompressNone, Arc::new(Reverse)).is_err());
+        assert!(register_compressor(CompressType::Custom(8), Arc::new(Reverse)).is_err());
+    }
+}
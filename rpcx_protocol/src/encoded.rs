@@ -0,0 +1,169 @@
+use std::{
+    borrow::Cow,
+    io::{self, IoSlice, Write},
+};
+
+/// an encoded frame kept in parts, for writing with vectored IO.
+///
+/// The payload (and the metadata of a `Frame`) are borrowed from the message
+/// unless they had to be compressed, so a large payload goes to the writer
+/// without being copied into an intermediate buffer.
+#[derive(Debug)]
+pub struct EncodedFrame<'a> {
+    /// the header, the frame length, service path and method and the metadata
+    /// length.
+    pub(crate) head: Vec<u8>,
+    pub(crate) metadata: Cow<'a, [u8]>,
+    pub(crate) payload_len: [u8; 4],
+    pub(crate) payload: Cow<'a, [u8]>,
+}
+
+impl<'a> EncodedFrame<'a> {
+    /// Returns the length of the whole frame.
+    pub fn len(&self) -> usize {
+        self.head.len() + self.metadata.len() + self.payload_len.len() + self.payload.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the header, metadata and payload as separate slices.
+    pub fn io_slices(&self) -> [IoSlice<'_>; 4] {
+        [
+            IoSlice::new(&self.head),
+            IoSlice::new(&self.metadata),
+            IoSlice::new(&self.payload_len),
+            IoSlice::new(&self.payload),
+        ]
+    }
+
+    /// Writes the whole frame with `write_vectored`, retrying short writes.
+    pub fn write_to<W>(&self, w: &mut W) -> io::Result<()>
+    where
+        W: Write + ?Sized,
+    {
+        let parts: [&[u8]; 4] = [&self.head, &self.metadata, &self.payload_len, &self.payload];
+        let (mut part, mut offset) = (0, 0);
+        while part < parts.len() {
+            let mut slices = [IoSlice::new(&[]); 4];
+            for (slice, (i, p)) in slices.iter_mut().zip(parts.iter().enumerate().skip(part)) {
+                *slice = IoSlice::new(if i == part { &p[offset..] } else { p });
+            }
+
+            let mut n = match w.write_vectored(&slices[..parts.len() - part]) {
+                Ok(0) if parts[part..].iter().any(|p| !p.is_empty()) => {
+                    return Err(io::ErrorKind::WriteZero.into());
+                }
+                Ok(n) => n,
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            };
+
+            // skip what was written, including empty parts
+            while part < parts.len() && n >= parts[part].len() - offset {
+                n -= parts[part].len() - offset;
+                part += 1;
+                offset = 0;
+            }
+            offset += n;
+        }
+        Ok(())
+    }
+
+    /// Copies the frame into one buffer.
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(self.len());
+        for part in self.io_slices().iter() {
+            buf.extend_from_slice(part);
+        }
+        buf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CompressType, Message, MessageBuilder, RpcxMessage};
+
+    /// a writer which accepts at most `max` bytes per call.
+    struct ShortWriter {
+        buf: Vec<u8>,
+        max: usize,
+        calls: usize,
+    }
+
+    impl Write for ShortWriter {
+        fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+            self.calls += 1;
+            let n = data.len().min(self.max);
+            self.buf.extend_from_slice(&data[..n]);
+            Ok(n)
+        }
+        fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+            self.calls += 1;
+            let mut n = 0;
+            for b in bufs {
+                let m = b.len().min(self.max - n);
+                self.buf.extend_from_slice(&b[..m]);
+                n += m;
+            }
+            Ok(n)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn message() -> Message {
+        MessageBuilder::new()
+            .seq(7)
+            .service_path("Arith")
+            .service_method("Mul")
+            .meta("__ID", "6ba7b810-9dad-11d1-80b4-00c04fd430c9")
+            .payload(vec![7u8; 1000])
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn borrow_payload() {
+        let msg = message();
+        let encoded = msg.encode_vectored();
+        assert_eq!(msg.payload.as_ptr(), encoded.payload.as_ptr());
+        assert_eq!(msg.encode(), encoded.to_vec());
+        assert_eq!(
+            encoded.len(),
+            encoded.io_slices().iter().map(|s| s.len()).sum::<usize>()
+        );
+    }
+
+    #[test]
+    fn short_writes() {
+        let msg = message();
+        for max in [1, 3, 16, 100, 5000].iter() {
+            let mut w = ShortWriter {
+                buf: Vec::new(),
+                max: *max,
+                calls: 0,
+            };
+            msg.encode_vectored().write_to(&mut w).unwrap();
+            assert_eq!(msg.encode(), w.buf);
+            if *max >= msg.encode().len() {
+                assert_eq!(1, w.calls);
+            }
+        }
+    }
+
+    #[test]
+    fn compressed_payload() {
+        let mut msg = message();
+        msg.set_compress_type(CompressType::Gzip).unwrap();
+        let encoded = msg.encode_vectored();
+        assert!(encoded.payload.len() < msg.payload.len());
+
+        let mut decoded = Message::new();
+        decoded.decode(&mut &encoded.to_vec()[..]).unwrap();
+        assert_eq!(msg.payload, decoded.payload);
+    }
+}
@@ -0,0 +1,443 @@
+use aes_gcm::{
+    aead::{consts::U12, Aead, AeadCore, NewAead, Payload},
+    Aes256Gcm,
+};
+use chacha20poly1305::ChaCha20Poly1305;
+
+use std::{collections::HashMap, fmt, sync::Arc};
+
+use crate::{
+    message::{compress, decompress, protocol_error},
+    CompressType, DecodeLimits, Message, MessageStatusType, Result, RpcxMessage, ENCRYPTION_KEY_ID,
+    ERROR_CODE_KEY, ERROR_DETAILS_KEY, ERROR_KIND_KEY, SERVICE_ERROR,
+};
+
+const NONCE_LEN: usize = 12;
+
+/// the AEAD cipher of an `EncryptionKey`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Cipher {
+    Aes256Gcm,
+    ChaCha20Poly1305,
+}
+
+/// a 256 bit key and the id it is announced with in the metadata.
+#[derive(Clone)]
+pub struct EncryptionKey {
+    pub id: String,
+    pub cipher: Cipher,
+    key: [u8; 32],
+}
+
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // never print the key itself
+        f.debug_struct("EncryptionKey")
+            .field("id", &self.id)
+            .field("cipher", &self.cipher)
+            .finish()
+    }
+}
+
+impl EncryptionKey {
+    pub fn new<S: Into<String>>(id: S, cipher: Cipher, key: [u8; 32]) -> Self {
+        EncryptionKey {
+            id: id.into(),
+            cipher,
+            key,
+        }
+    }
+
+    /// Encrypts `data`, returning the random nonce followed by the
+    /// ciphertext and the tag.
+    pub fn seal(&self, aad: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+        match self.cipher {
+            Cipher::Aes256Gcm => seal_with::<Aes256Gcm>(&self.key, aad, data),
+            Cipher::ChaCha20Poly1305 => seal_with::<ChaCha20Poly1305>(&self.key, aad, data),
+        }
+    }
+
+    /// Decrypts data written by `seal`, failing if it or `aad` were changed.
+    pub fn open(&self, aad: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+        match self.cipher {
+            Cipher::Aes256Gcm => open_with::<Aes256Gcm>(&self.key, aad, data),
+            Cipher::ChaCha20Poly1305 => open_with::<ChaCha20Poly1305>(&self.key, aad, data),
+        }
+    }
+}
+
+fn seal_with<C>(key: &[u8], aad: &[u8], data: &[u8]) -> Result<Vec<u8>>
+where
+    C: Aead + NewAead + AeadCore<NonceSize = U12>,
+{
+    let cipher = C::new_from_slice(key).map_err(|_| protocol_error("invalid key length"))?;
+    let nonce: [u8; NONCE_LEN] = rand::random();
+    let sealed = cipher
+        .encrypt((&nonce).into(), Payload { msg: data, aad })
+        .map_err(|_| protocol_error("failed to encrypt the payload"))?;
+
+    let mut buf = Vec::with_capacity(NONCE_LEN + sealed.len());
+    buf.extend_from_slice(&nonce);
+    buf.extend_from_slice(&sealed);
+    Ok(buf)
+}
+
+fn open_with<C>(key: &[u8], aad: &[u8], data: &[u8]) -> Result<Vec<u8>>
+where
+    C: Aead + NewAead + AeadCore<NonceSize = U12>,
+{
+    let cipher = C::new_from_slice(key).map_err(|_| protocol_error("invalid key length"))?;
+    if data.len() < NONCE_LEN {
+        return Err(protocol_error("encrypted payload is truncated"));
+    }
+    let (nonce, sealed) = data.split_at(NONCE_LEN);
+    let mut nonce_buf = [0u8; NONCE_LEN];
+    nonce_buf.copy_from_slice(nonce);
+    cipher
+        .decrypt((&nonce_buf).into(), Payload { msg: sealed, aad })
+        .map_err(|_| protocol_error("failed to decrypt the payload"))
+}
+
+/// supplies the keys for an `Encryption`.
+///
+/// Keys are looked up by the id sent with each message, so a provider can
+/// rotate keys while messages sealed with older ones are still in flight.
+pub trait KeyProvider: Send + Sync {
+    /// Returns the key new messages are encrypted with.
+    fn current_key(&self) -> Result<EncryptionKey>;
+    /// Returns the key with `id`, `None` if it is unknown.
+    fn key(&self, id: &str) -> Option<EncryptionKey>;
+}
+
+/// a fixed set of keys.
+#[derive(Debug, Clone)]
+pub struct StaticKeyProvider {
+    current: EncryptionKey,
+    keys: HashMap<String, EncryptionKey>,
+}
+
+impl StaticKeyProvider {
+    /// Creates a provider which encrypts with `key`.
+    pub fn new(key: EncryptionKey) -> Self {
+        let mut keys = HashMap::new();
+        keys.insert(key.id.clone(), key.clone());
+        StaticKeyProvider { current: key, keys }
+    }
+
+    /// Adds a key which is only used for decryption, e.g. the previous key
+    /// while keys are rotated.
+    pub fn add_key(&mut self, key: EncryptionKey) {
+        self.keys.insert(key.id.clone(), key);
+    }
+}
+
+impl KeyProvider for StaticKeyProvider {
+    fn current_key(&self) -> Result<EncryptionKey> {
+        Ok(self.current.clone())
+    }
+
+    fn key(&self, id: &str) -> Option<EncryptionKey> {
+        self.keys.get(id).cloned()
+    }
+}
+
+/// encrypts payloads end to end, after they are compressed.
+///
+/// `seal` compresses the payload as announced in the header, encrypts it and
+/// sends the key id in the metadata. `encode` and `decode` leave encrypted
+/// payloads as they are, `open` decrypts and decompresses them. The message
+/// type and status, compress and serialize types, seq, service path and
+/// service method are authenticated with the payload, and so is the error of
+/// error replies, which are sealed with their empty payload.
+#[derive(Clone)]
+pub struct Encryption {
+    provider: Arc<dyn KeyProvider>,
+}
+
+impl fmt::Debug for Encryption {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Encryption").finish()
+    }
+}
+
+impl Encryption {
+    pub fn new(provider: Arc<dyn KeyProvider>) -> Self {
+        Encryption { provider }
+    }
+
+    /// Encrypts the payload of `msg` with the current key.
+    pub fn seal(&self, msg: &mut Message) -> Result<()> {
+        if msg.is_encrypted() {
+            return Err(protocol_error("message is already encrypted"));
+        }
+
+        let key = self.provider.current_key()?;
+        let mut header = msg.header;
+        let payload = compress(&mut header, &msg.payload).into_owned();
+        msg.header = header;
+        msg.payload = key.seal(&aad(msg), &payload)?;
+        msg.metadata.insert(ENCRYPTION_KEY_ID.to_owned(), key.id);
+        Ok(())
+    }
+
+    /// Decrypts the payload of a message sealed by `seal`, failing if it
+    /// isn't encrypted or the key is unknown. The payload is decompressed
+    /// up to `limits.max_payload_size`.
+    pub fn open(&self, msg: &mut Message, limits: &DecodeLimits) -> Result<()> {
+        let id = msg
+            .metadata
+            .get(ENCRYPTION_KEY_ID)
+            .ok_or_else(|| protocol_error("message is not encrypted"))?;
+        let key = self
+            .provider
+            .key(id)
+            .ok_or_else(|| protocol_error("unknown encryption key"))?;
+
+        let payload = key.open(&aad(msg), &msg.payload)?;
+        let ct = msg
+            .get_compress_type()
+            .ok_or_else(|| protocol_error("unknown compress type"))?;
+        msg.payload = match ct {
+            CompressType::CompressNone => payload,
+            _ => decompress(ct, &payload, limits.max_payload_size)?,
+        };
+        msg.metadata.remove(ENCRYPTION_KEY_ID);
+        Ok(())
+    }
+}
+
+/// Returns the parts of a message which are authenticated with its payload.
+fn aad(msg: &Message) -> Vec<u8> {
+    let mut aad = Vec::with_capacity(2 + 8 + msg.service_path.len() + 1 + msg.service_method.len());
+    // the message type, compress and status bits, and the serialize type
+    aad.push(msg.header[2] & 0x9F);
+    aad.push(msg.header[3]);
+    aad.extend_from_slice(&msg.header[4..]);
+    aad.extend_from_slice(msg.service_path.as_bytes());
+    aad.push(0);
+    aad.extend_from_slice(msg.service_method.as_bytes());
+    if msg.get_message_status_type() == Some(MessageStatusType::Error) {
+        for key in [
+            SERVICE_ERROR,
+            ERROR_KIND_KEY,
+            ERROR_CODE_KEY,
+            ERROR_DETAILS_KEY,
+        ]
+        .iter()
+        {
+            match msg.metadata.get(*key) {
+                Some(value) => {
+                    aad.push(1);
+                    aad.extend_from_slice(&(value.len() as u32).to_be_bytes());
+                    aad.extend_from_slice(value.as_bytes());
+                }
+                None => aad.push(0),
+            }
+        }
+    }
+    aad
+}
+
+impl Message {
+    /// Returns true if the payload was encrypted by `Encryption::seal`.
+    pub fn is_encrypted(&self) -> bool {
+        self.metadata.contains_key(ENCRYPTION_KEY_ID)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Error, ErrorKind, Frame, MessageBuilder, MessageType};
+
+    fn encryption(cipher: Cipher) -> Encryption {
+        let key = EncryptionKey::new("k1", cipher, [7u8; 32]);
+        Encryption::new(Arc::new(StaticKeyProvider::new(key)))
+    }
+
+    fn message() -> Message {
+        MessageBuilder::new()
+            .compress_type(CompressType::Gzip)
+            .seq(42)
+            .service_path("Arith")
+            .service_method("Mul")
+            .payload(b"{\"A\":10,\"B\":20}".repeat(10))
+            .build()
+            .unwrap()
+    }
+
+    fn decode(data: &[u8]) -> Message {
+        let mut msg = Message::new();
+        msg.decode(&mut &data[..]).unwrap();
+        msg
+    }
+
+    #[test]
+    fn seal_and_open() {
+        for cipher in [Cipher::Aes256Gcm, Cipher::ChaCha20Poly1305].iter() {
+            let encryption = encryption(*cipher);
+            let plain = message();
+            let mut msg = message();
+            encryption.seal(&mut msg).unwrap();
+            assert!(msg.is_encrypted());
+            assert_eq!("k1", msg.metadata[ENCRYPTION_KEY_ID]);
+            // compressed before it was encrypted
+            assert!(msg.payload.len() < plain.payload.len());
+            assert!(encryption.seal(&mut msg).is_err());
+
+            // the encrypted payload goes through encode and decode unchanged
+            let mut decoded = decode(&msg.encode());
+            assert_eq!(msg.payload, decoded.payload);
+            assert_eq!(Some(CompressType::Gzip), decoded.get_compress_type());
+            let mut frame = Frame::new();
+            frame.decode(&mut &msg.encode()[..]).unwrap();
+            assert_eq!(msg.payload, frame.to_message().unwrap().payload);
+
+            encryption
+                .open(&mut decoded, &DecodeLimits::default())
+                .unwrap();
+            assert!(!decoded.is_encrypted());
+            assert_eq!(plain.payload, decoded.payload);
+            assert!(encryption
+                .open(&mut decoded, &DecodeLimits::default())
+                .is_err());
+        }
+    }
+
+    #[test]
+    fn reject_tampering() {
+        let encryption = encryption(Cipher::Aes256Gcm);
+        let mut msg = message();
+        encryption.seal(&mut msg).unwrap();
+
+        let mut changed = decode(&msg.encode());
+        *changed.payload.last_mut().unwrap() ^= 0x01;
+        assert!(encryption
+            .open(&mut changed, &DecodeLimits::default())
+            .is_err());
+
+        // a request replayed as the reply of another method
+        let mut changed = decode(&msg.encode());
+        changed.service_method = "Add".to_owned();
+        assert!(encryption
+            .open(&mut changed, &DecodeLimits::default())
+            .is_err());
+        let mut changed = decode(&msg.encode());
+        changed.set_message_type(MessageType::Response);
+        assert!(encryption
+            .open(&mut changed, &DecodeLimits::default())
+            .is_err());
+
+        // and keys which aren't known or don't match
+        let other = Encryption::new(Arc::new(StaticKeyProvider::new(EncryptionKey::new(
+            "k1",
+            Cipher::Aes256Gcm,
+            [8u8; 32],
+        ))));
+        assert!(other
+            .open(&mut decode(&msg.encode()), &DecodeLimits::default())
+            .is_err());
+        let mut changed = decode(&msg.encode());
+        changed
+            .metadata
+            .insert(ENCRYPTION_KEY_ID.to_owned(), "k2".to_owned());
+        assert!(encryption
+            .open(&mut changed, &DecodeLimits::default())
+            .is_err());
+    }
+
+    #[test]
+    fn seal_errors() {
+        let encryption = encryption(Cipher::Aes256Gcm);
+        let mut reply = message().get_reply().unwrap();
+        reply.set_reply_error(&Error::new(ErrorKind::NotFound, "method Mul not found"));
+        encryption.seal(&mut reply).unwrap();
+
+        let mut decoded = decode(&reply.encode());
+        encryption
+            .open(&mut decoded, &DecodeLimits::default())
+            .unwrap();
+        assert_eq!(ErrorKind::NotFound, decoded.reply_error().unwrap().kind());
+
+        // the error can't be changed
+        let mut changed = decode(&reply.encode());
+        changed.metadata.insert(
+            ERROR_KIND_KEY.to_owned(),
+            ErrorKind::Unavailable.name().to_owned(),
+        );
+        assert!(encryption
+            .open(&mut changed, &DecodeLimits::default())
+            .is_err());
+        let mut changed = decode(&reply.encode());
+        changed.metadata.remove(ERROR_KIND_KEY);
+        assert!(encryption
+            .open(&mut changed, &DecodeLimits::default())
+            .is_err());
+
+        // nor can a reply be turned into an error
+        let mut reply = message().get_reply().unwrap();
+        encryption.seal(&mut reply).unwrap();
+        let mut changed = decode(&reply.encode());
+        changed.set_message_status_type(MessageStatusType::Error);
+        assert!(encryption
+            .open(&mut changed, &DecodeLimits::default())
+            .is_err());
+    }
+
+    #[test]
+    fn limit_decompression() {
+        let encryption = encryption(Cipher::Aes256Gcm);
+        let mut msg = message();
+        encryption.seal(&mut msg).unwrap();
+
+        // the payload inflates to 150 bytes
+        let limits = DecodeLimits {
+            max_payload_size: 100,
+            ..Default::default()
+        };
+        let err = encryption
+            .open(&mut decode(&msg.encode()), &limits)
+            .unwrap_err();
+        assert_eq!(ErrorKind::Protocol, err.kind());
+        let limits = DecodeLimits {
+            max_payload_size: 150,
+            ..Default::default()
+        };
+        encryption.open(&mut msg, &limits).unwrap();
+        assert_eq!(message().payload, msg.payload);
+    }
+
+    #[test]
+    fn rotate_keys() {
+        let old = encryption(Cipher::Aes256Gcm);
+        let mut msg = message();
+        old.seal(&mut msg).unwrap();
+
+        let mut provider = StaticKeyProvider::new(EncryptionKey::new(
+            "k2",
+            Cipher::ChaCha20Poly1305,
+            [9u8; 32],
+        ));
+        provider.add_key(EncryptionKey::new("k1", Cipher::Aes256Gcm, [7u8; 32]));
+        let new = Encryption::new(Arc::new(provider));
+        new.open(&mut msg, &DecodeLimits::default()).unwrap();
+        assert_eq!(message().payload, msg.payload);
+
+        new.seal(&mut msg).unwrap();
+        assert_eq!("k2", msg.metadata[ENCRYPTION_KEY_ID]);
+        assert!(old
+            .open(&mut decode(&msg.encode()), &DecodeLimits::default())
+            .is_err());
+        new.open(&mut msg, &DecodeLimits::default()).unwrap();
+        assert_eq!(message().payload, msg.payload);
+    }
+
+    #[test]
+    fn debug_hides_key() {
+        let key = EncryptionKey::new("k1", Cipher::Aes256Gcm, [7u8; 32]);
+        assert_eq!(
+            "EncryptionKey { id: \"k1\", cipher: Aes256Gcm }",
+            format!("{:?}", key)
+        );
+    }
+}
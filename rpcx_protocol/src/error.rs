@@ -33,6 +33,20 @@ pub enum ErrorKind {
     Server,
     Serialization,
     Other,
+    /// the call took longer than allowed.
+    Timeout,
+    /// the service or method doesn't exist.
+    NotFound,
+    /// the server can't be reached or is going away.
+    Unavailable,
+    /// the call was canceled before its reply arrived.
+    Canceled,
+    /// the caller isn't authenticated.
+    Unauthenticated,
+    /// the caller may not call the method.
+    PermissionDenied,
+    /// a limit of the client or server was hit.
+    ResourceExhausted,
 }
 
 impl ErrorKind {
@@ -45,8 +59,71 @@ impl ErrorKind {
             ErrorKind::Server => "server error",
             ErrorKind::Serialization => "serialization failure",
             ErrorKind::Other => "other",
+            ErrorKind::Timeout => "timed out",
+            ErrorKind::NotFound => "not found",
+            ErrorKind::Unavailable => "unavailable",
+            ErrorKind::Canceled => "canceled",
+            ErrorKind::Unauthenticated => "unauthenticated",
+            ErrorKind::PermissionDenied => "permission denied",
+            ErrorKind::ResourceExhausted => "resource exhausted",
         }
     }
+
+    /// Returns the name the kind is sent with in `ERROR_KIND_KEY`.
+    pub fn name(self) -> &'static str {
+        match self {
+            ErrorKind::Protocol => "protocol",
+            ErrorKind::IO => "io",
+            ErrorKind::Client => "client",
+            ErrorKind::Network => "network",
+            ErrorKind::Server => "server",
+            ErrorKind::Serialization => "serialization",
+            ErrorKind::Other => "other",
+            ErrorKind::Timeout => "timeout",
+            ErrorKind::NotFound => "not_found",
+            ErrorKind::Unavailable => "unavailable",
+            ErrorKind::Canceled => "canceled",
+            ErrorKind::Unauthenticated => "unauthenticated",
+            ErrorKind::PermissionDenied => "permission_denied",
+            ErrorKind::ResourceExhausted => "resource_exhausted",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<ErrorKind> {
+        let kind = match name {
+            "protocol" => ErrorKind::Protocol,
+            "io" => ErrorKind::IO,
+            "client" => ErrorKind::Client,
+            "network" => ErrorKind::Network,
+            "server" => ErrorKind::Server,
+            "serialization" => ErrorKind::Serialization,
+            "other" => ErrorKind::Other,
+            "timeout" => ErrorKind::Timeout,
+            "not_found" => ErrorKind::NotFound,
+            "unavailable" => ErrorKind::Unavailable,
+            "canceled" => ErrorKind::Canceled,
+            "unauthenticated" => ErrorKind::Unauthenticated,
+            "permission_denied" => ErrorKind::PermissionDenied,
+            "resource_exhausted" => ErrorKind::ResourceExhausted,
+            _ => return None,
+        };
+        Some(kind)
+    }
+
+    /// Tells whether a call failing this way may succeed when it is sent
+    /// again, possibly to another server. A call which failed with `Network`
+    /// or `Timeout` may have run already, so only send it again if it is
+    /// idempotent.
+    pub fn is_retryable(self) -> bool {
+        matches!(
+            self,
+            ErrorKind::Client
+                | ErrorKind::Network
+                | ErrorKind::Timeout
+                | ErrorKind::Unavailable
+                | ErrorKind::ResourceExhausted
+        )
+    }
 }
 
 impl From<&'static str> for Error {
@@ -77,7 +154,17 @@ impl From<serde_json::error::Error> for Error {
 impl From<std::io::Error> for Error {
     #[inline]
     fn from(err: std::io::Error) -> Error {
-        Error::new(ErrorKind::IO, err)
+        use std::io::ErrorKind as IoKind;
+        let kind = match err.kind() {
+            IoKind::TimedOut | IoKind::WouldBlock => ErrorKind::Timeout,
+            IoKind::ConnectionRefused
+            | IoKind::ConnectionReset
+            | IoKind::ConnectionAborted
+            | IoKind::NotConnected
+            | IoKind::BrokenPipe => ErrorKind::Unavailable,
+            _ => ErrorKind::IO,
+        };
+        Error::new(kind, err)
     }
 }
 
@@ -141,6 +228,11 @@ impl Error {
             Repr::Simple(kind) => kind,
         }
     }
+
+    /// See `ErrorKind::is_retryable`.
+    pub fn is_retryable(&self) -> bool {
+        self.kind().is_retryable()
+    }
 }
 
 impl fmt::Debug for Repr {
@@ -187,3 +279,38 @@ impl error::Error for Error {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+
+    #[test]
+    fn kinds() {
+        for kind in [
+            ErrorKind::Protocol,
+            ErrorKind::NotFound,
+            ErrorKind::PermissionDenied,
+            ErrorKind::ResourceExhausted,
+        ]
+        .iter()
+        {
+            assert_eq!(Some(*kind), ErrorKind::from_name(kind.name()));
+        }
+        assert_eq!(None, ErrorKind::from_name("NotFound"));
+
+        assert!(Error::new(ErrorKind::Unavailable, "draining").is_retryable());
+        assert!(!Error::new(ErrorKind::NotFound, "no such method").is_retryable());
+        assert!(!Error::from("service failed").is_retryable());
+    }
+
+    #[test]
+    fn io_errors() {
+        let err = Error::from(io::Error::new(io::ErrorKind::ConnectionRefused, "refused"));
+        assert_eq!(ErrorKind::Unavailable, err.kind());
+        let err = Error::from(io::Error::new(io::ErrorKind::WouldBlock, "read timeout"));
+        assert_eq!(ErrorKind::Timeout, err.kind());
+        let err = Error::from(io::Error::new(io::ErrorKind::UnexpectedEof, "eof"));
+        assert_eq!(ErrorKind::IO, err.kind());
+    }
+}
@@ -0,0 +1,402 @@
+use std::collections::HashMap;
+
+use crate::{message::protocol_error, Error, Message, Result, RpcxMessage, FRAGMENT_KEY};
+
+/// the handshake feature of peers which reassemble fragments.
+pub const FRAGMENT_FEATURE: &str = "fragment";
+
+/// the default limit of the bytes buffered to reassemble the messages of one
+/// connection. Peers which exchange larger messages have to raise it.
+pub const DEFAULT_MAX_REASSEMBLY_SIZE: usize = 4 << 20;
+
+/// Splits `msg` into messages whose payloads hold at most `max_len` bytes.
+///
+/// All fragments share the header, service path, method and metadata of
+/// `msg`, and carry their position as `index/count` under `FRAGMENT_KEY`. A
+/// message whose payload fits is returned as it is. The chunks are split off
+/// the end of the payload, which is shrunk after each, so not much more than
+/// the payload is held at once. Only send fragments to peers which agreed on
+/// `FRAGMENT_FEATURE`.
+pub fn split(mut msg: Message, max_len: usize) -> Vec<Message> {
+    if max_len == 0 || msg.payload.len() <= max_len {
+        return vec![msg];
+    }
+
+    let mut payload = std::mem::take(&mut msg.payload);
+    let count = payload.chunks(max_len).len();
+    let mut chunks = Vec::with_capacity(count);
+    for index in (1..count).rev() {
+        chunks.push(payload.split_off(index * max_len));
+        payload.shrink_to_fit();
+    }
+    chunks.push(payload);
+
+    chunks
+        .into_iter()
+        .rev()
+        .enumerate()
+        .map(|(index, chunk)| {
+            let mut fragment = Message::new();
+            fragment.header = msg.header;
+            fragment.service_path = msg.service_path.clone();
+            fragment.service_method = msg.service_method.clone();
+            fragment.metadata = msg.metadata.clone();
+            fragment
+                .metadata
+                .insert(FRAGMENT_KEY.to_owned(), format!("{}/{}", index, count));
+            fragment.payload = chunk;
+            fragment
+        })
+        .collect()
+}
+
+/// the most messages a `Reassembler` reassembles at once, including the
+/// rejected ones whose remaining fragments it still drops.
+pub const MAX_PARTIALS: usize = 1024;
+
+/// the bytes an incomplete message is charged for besides its header and
+/// payload, roughly the size of its entry.
+const PARTIAL_OVERHEAD: usize = 128;
+
+/// a message whose fragments are still arriving.
+#[derive(Debug)]
+struct Partial {
+    msg: Message,
+    next: usize,
+    count: usize,
+    /// the bytes charged for the message.
+    size: usize,
+    /// set once the message is rejected, its remaining fragments are dropped.
+    failed: bool,
+}
+
+/// reassembles messages split by `split`, one per connection and direction.
+///
+/// Fragments of one message must arrive in order, fragments of different
+/// messages may interleave. Each message is buffered whole until its last
+/// fragment arrived, so the limit must fit the largest message expected. The
+/// headers and payloads of all incomplete messages together may not exceed
+/// the limit, and at most `MAX_PARTIALS` messages are incomplete at once. A
+/// message which breaks these rules is rejected once and its remaining
+/// fragments are dropped.
+#[derive(Debug)]
+pub struct Reassembler {
+    max_len: usize,
+    buffered: usize,
+    partials: HashMap<u64, Partial>,
+}
+
+impl Reassembler {
+    /// Creates a reassembler which buffers at most `max_len` bytes.
+    pub fn new(max_len: usize) -> Self {
+        Reassembler {
+            max_len,
+            buffered: 0,
+            partials: HashMap::new(),
+        }
+    }
+
+    /// Returns the number of bytes charged for incomplete messages, their
+    /// headers and payloads.
+    pub fn buffered(&self) -> usize {
+        self.buffered
+    }
+
+    /// Adds a decoded message. Returns it when it is complete: at once if it
+    /// isn't a fragment, with the joined payload after its last fragment.
+    pub fn push(&mut self, mut msg: Message) -> Result<Option<Message>> {
+        let (index, count) = match msg.metadata.remove(FRAGMENT_KEY) {
+            Some(pos) => parse_position(&pos)?,
+            None => return Ok(Some(msg)),
+        };
+        let seq = msg.get_seq();
+
+        match self.partials.get(&seq) {
+            Some(partial) if partial.failed => {
+                if index + 1 == count {
+                    self.partials.remove(&seq);
+                }
+                return Ok(None);
+            }
+            Some(_) if index == 0 => {
+                return Err(self.reject(
+                    seq,
+                    index,
+                    count,
+                    "fragments of two messages share a seq",
+                ));
+            }
+            Some(_) => {}
+            None if index != 0 => {
+                return Err(self.reject(seq, index, count, "fragment without its first fragment"));
+            }
+            None if self.partials.len() >= MAX_PARTIALS => {
+                return Err(protocol_error("too many fragmented messages"));
+            }
+            None => {
+                self.partials.insert(
+                    seq,
+                    Partial {
+                        msg: Message::new(),
+                        next: 0,
+                        count,
+                        size: 0,
+                        failed: false,
+                    },
+                );
+            }
+        }
+
+        let partial = self.partials.get_mut(&seq).unwrap();
+        if partial.next != index || partial.count != count {
+            return Err(self.reject(seq, index, count, "fragments are out of order"));
+        }
+        let size = if index == 0 {
+            head_len(&msg) + msg.payload.len()
+        } else {
+            msg.payload.len()
+        };
+        if self.buffered + size > self.max_len {
+            return Err(self.reject(
+                seq,
+                index,
+                count,
+                "fragmented message exceeds the memory limit",
+            ));
+        }
+        self.buffered += size;
+        partial.size += size;
+        partial.next += 1;
+        if index == 0 {
+            partial.msg = msg;
+        } else {
+            partial.msg.payload.extend_from_slice(&msg.payload);
+        }
+
+        if partial.next < partial.count {
+            return Ok(None);
+        }
+        let partial = self.partials.remove(&seq).unwrap();
+        self.buffered -= partial.size;
+        Ok(Some(partial.msg))
+    }
+
+    /// Drops what is buffered of the message with `seq`. Unless the fragment
+    /// at `index` was its last one, the rest of its fragments are dropped
+    /// without errors.
+    fn reject(&mut self, seq: u64, index: usize, count: usize, reason: &'static str) -> Error {
+        let last = index + 1 == count;
+        let room = self.partials.len() < MAX_PARTIALS;
+        match self.partials.get_mut(&seq) {
+            Some(partial) => {
+                self.buffered -= partial.size;
+                if last {
+                    self.partials.remove(&seq);
+                } else {
+                    partial.msg = Message::new();
+                    partial.size = 0;
+                    partial.failed = true;
+                }
+            }
+            None if !last && room => {
+                self.partials.insert(
+                    seq,
+                    Partial {
+                        msg: Message::new(),
+                        next: index,
+                        count,
+                        size: 0,
+                        failed: true,
+                    },
+                );
+            }
+            None => {}
+        }
+        protocol_error(reason)
+    }
+}
+
+/// Returns the bytes charged for the header of the first fragment.
+fn head_len(msg: &Message) -> usize {
+    let metadata: usize = msg.metadata.iter().map(|(k, v)| k.len() + v.len()).sum();
+    PARTIAL_OVERHEAD + msg.service_path.len() + msg.service_method.len() + metadata
+}
+
+fn parse_position(pos: &str) -> Result<(usize, usize)> {
+    let mut parts = pos.splitn(2, '/');
+    let index = parts.next().and_then(|s| s.parse().ok());
+    let count = parts.next().and_then(|s| s.parse().ok());
+    match (index, count) {
+        (Some(index), Some(count)) if index < count => Ok((index, count)),
+        _ => Err(protocol_error("invalid fragment position")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CompressType, ErrorKind, MessageBuilder};
+
+    fn message(seq: u64, len: usize) -> Message {
+        MessageBuilder::new()
+            .compress_type(CompressType::Gzip)
+            .seq(seq)
+            .service_path("Batch")
+            .service_method("Export")
+            .meta("__ID", "6ba7b810-9dad-11d1-80b4-00c04fd430c9")
+            .payload((0..len).map(|i| i as u8).collect())
+            .build()
+            .unwrap()
+    }
+
+    /// encodes and decodes `msgs`, like sending them over a connection.
+    fn transfer(msgs: Vec<Message>) -> Vec<Message> {
+        msgs.iter()
+            .map(|msg| {
+                let mut decoded = Message::new();
+                decoded.decode(&mut &msg.encode()[..]).unwrap();
+                decoded
+            })
+            .collect()
+    }
+
+    #[test]
+    fn split_and_reassemble() {
+        let fragments = split(message(1, 1000), 300);
+        assert_eq!(4, fragments.len());
+        assert_eq!("3/4", fragments[3].metadata[FRAGMENT_KEY]);
+        assert_eq!(100, fragments[3].payload.len());
+
+        let mut reassembler = Reassembler::new(2000);
+        let mut done = Vec::new();
+        for fragment in transfer(fragments) {
+            done.extend(reassembler.push(fragment).unwrap());
+        }
+        assert_eq!(1, done.len());
+        let msg = &done[0];
+        assert_eq!(message(1, 1000).payload, msg.payload);
+        assert_eq!("Export", msg.service_method);
+        assert!(!msg.metadata.contains_key(FRAGMENT_KEY));
+        assert_eq!(1, msg.metadata.len());
+        assert_eq!(0, reassembler.buffered());
+
+        // small messages aren't split
+        let fragments = split(message(2, 300), 300);
+        assert_eq!(1, fragments.len());
+        assert!(!fragments[0].metadata.contains_key(FRAGMENT_KEY));
+        assert!(reassembler.push(message(2, 300)).unwrap().is_some());
+    }
+
+    #[test]
+    fn interleaved_messages() {
+        let a = transfer(split(message(1, 500), 200));
+        let b = transfer(split(message(2, 300), 200));
+
+        let mut reassembler = Reassembler::new(2000);
+        let mut done = Vec::new();
+        let mut a = a.into_iter();
+        let mut b = b.into_iter();
+        for fragment in [a.next(), b.next(), a.next(), b.next(), a.next()].iter_mut() {
+            done.extend(reassembler.push(fragment.take().unwrap()).unwrap());
+        }
+        assert_eq!(
+            vec![2, 1],
+            done.iter().map(|m| m.get_seq()).collect::<Vec<_>>()
+        );
+        assert_eq!(message(1, 500).payload, done[1].payload);
+    }
+
+    #[test]
+    fn memory_limit() {
+        let mut reassembler = Reassembler::new(500);
+        let mut fragments = split(message(1, 1000), 300).into_iter();
+        let first = fragments.next().unwrap();
+        assert!(reassembler.push(first).unwrap().is_none());
+        assert_eq!(300 + head_len(&message(1, 0)), reassembler.buffered());
+        let err = reassembler.push(fragments.next().unwrap()).unwrap_err();
+        assert_eq!(ErrorKind::Protocol, err.kind());
+        assert_eq!(0, reassembler.buffered());
+
+        // the rest of the message is dropped without further errors
+        for fragment in fragments {
+            assert!(reassembler.push(fragment).unwrap().is_none());
+        }
+        assert!(reassembler.partials.is_empty());
+        assert!(reassembler.push(message(2, 400)).unwrap().is_some());
+    }
+
+    #[test]
+    fn invalid_fragments() {
+        let mut reassembler = Reassembler::new(1000);
+        let mut fragments = split(message(1, 1000), 300).into_iter();
+        let first = fragments.next().unwrap();
+        let _ = fragments.next();
+        reassembler.push(first).unwrap();
+        assert!(reassembler.push(fragments.next().unwrap()).is_err());
+
+        let mut fragments = split(message(2, 1000), 300);
+        assert!(reassembler.push(fragments.remove(1)).is_err());
+
+        let mut msg = message(3, 10);
+        msg.metadata
+            .insert(FRAGMENT_KEY.to_owned(), "2/2".to_owned());
+        assert!(reassembler.push(msg).is_err());
+    }
+
+    #[test]
+    fn rejected_once() {
+        // a skipped fragment
+        let mut reassembler = Reassembler::new(1000);
+        let mut fragments = split(message(1, 1000), 200).into_iter();
+        reassembler.push(fragments.next().unwrap()).unwrap();
+        let _ = fragments.next();
+        assert!(reassembler.push(fragments.next().unwrap()).is_err());
+        assert_eq!(0, reassembler.buffered());
+        for fragment in fragments {
+            assert!(reassembler.push(fragment).unwrap().is_none());
+        }
+        assert!(reassembler.partials.is_empty());
+
+        // a missing first fragment
+        let mut fragments = split(message(2, 1000), 200).into_iter().skip(1);
+        assert!(reassembler.push(fragments.next().unwrap()).is_err());
+        for fragment in fragments {
+            assert!(reassembler.push(fragment).unwrap().is_none());
+        }
+        assert!(reassembler.partials.is_empty());
+    }
+
+    #[test]
+    fn header_limit() {
+        // the metadata of incomplete messages counts against the limit
+        let mut msg = message(1, 10);
+        msg.metadata.insert("__Batch".to_owned(), "x".repeat(1000));
+        let mut reassembler = Reassembler::new(1000);
+        let mut fragments = split(msg, 5).into_iter();
+        let err = reassembler.push(fragments.next().unwrap()).unwrap_err();
+        assert_eq!(ErrorKind::Protocol, err.kind());
+        assert!(reassembler
+            .push(fragments.next().unwrap())
+            .unwrap()
+            .is_none());
+        assert!(reassembler.partials.is_empty());
+        assert_eq!(0, reassembler.buffered());
+    }
+
+    #[test]
+    fn partial_limit() {
+        let mut reassembler = Reassembler::new(usize::MAX);
+        for seq in 0..MAX_PARTIALS as u64 {
+            let first = split(message(seq, 2), 1).remove(0);
+            assert!(reassembler.push(first).unwrap().is_none());
+        }
+        let mut fragments = split(message(MAX_PARTIALS as u64, 2), 1);
+        assert!(reassembler.push(fragments.remove(0)).is_err());
+
+        // completing a message makes room for another one
+        let last = split(message(0, 2), 1).remove(1);
+        assert!(reassembler.push(last).unwrap().is_some());
+        let first = split(message(MAX_PARTIALS as u64 + 1, 2), 1).remove(0);
+        assert!(reassembler.push(first).unwrap().is_none());
+    }
+}
@@ -0,0 +1,351 @@
+use byteorder::{BigEndian, ByteOrder};
+use bytes::{Bytes, BytesMut};
+
+use std::{borrow::Cow, io::Read, str};
+
+use crate::{
+    message::{
+        check_header, checksum_key, decode_metadata, decompress, encode_metadata, protocol_error,
+        put_chunk, verify_checksum, wire_metadata, wire_payload, write_len, BodyRanges,
+        MetadataIter, MAGIC_NUMBER,
+    },
+    DecodeLimits, EncodedFrame, Error, ErrorKind, Message, MessageStatusType, Metadata, Result,
+    RpcxMessage, ENCRYPTION_KEY_ID, SERVICE_ERROR,
+};
+
+/// a zero-copy rpcx message.
+///
+/// Unlike `Message`, a `Frame` keeps the service path, service method and
+/// payload as `Bytes` slices of the buffer it was decoded from. Metadata is
+/// kept in its encoded form and only parsed when it is read, and the payload
+/// is kept as it was on the wire, so a proxy can forward a compressed payload
+/// without ever inflating it. A payload checksum is verified when a frame is
+/// decoded, but not updated when the payload is replaced.
+#[derive(Debug, Clone)]
+pub struct Frame {
+    pub header: [u8; 12],
+    pub service_path: Bytes,
+    pub service_method: Bytes,
+    metadata: Bytes,
+    pub payload: Bytes,
+}
+
+impl Default for Frame {
+    fn default() -> Self {
+        let mut header = [0u8; 12];
+        header[0] = MAGIC_NUMBER;
+        Frame {
+            header,
+            service_path: Bytes::new(),
+            service_method: Bytes::new(),
+            metadata: Bytes::new(),
+            payload: Bytes::new(),
+        }
+    }
+}
+
+impl Frame {
+    /// Creates a new `Frame`
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Builds a frame from its header and a body buffer (everything after
+    /// the length prefix). No bytes are copied.
+    pub fn from_parts(header: [u8; 12], body: Bytes) -> Result<Self> {
+        Self::from_parts_with_limits(header, body, &DecodeLimits::default())
+    }
+
+    /// Builds a frame like `from_parts`, rejecting bodies which exceed `limits`.
+    pub fn from_parts_with_limits(
+        header: [u8; 12],
+        body: Bytes,
+        limits: &DecodeLimits,
+    ) -> Result<Self> {
+        check_header(&header)?;
+        let ranges = BodyRanges::parse(&body, limits)?;
+        str::from_utf8(&body[ranges.service_path.clone()])
+            .map_err(|err| Error::new(ErrorKind::Protocol, err))?;
+        str::from_utf8(&body[ranges.service_method.clone()])
+            .map_err(|err| Error::new(ErrorKind::Protocol, err))?;
+
+        let frame = Frame {
+            header,
+            service_path: body.slice(ranges.service_path),
+            service_method: body.slice(ranges.service_method),
+            metadata: body.slice(ranges.metadata),
+            payload: body.slice(ranges.payload),
+        };
+        verify_checksum(frame.get_metadata(checksum_key(&header))?, &frame.payload)?;
+        Ok(frame)
+    }
+
+    pub fn service_path_str(&self) -> &str {
+        // validated in from_parts or copied from a String
+        str::from_utf8(&self.service_path).unwrap_or_default()
+    }
+
+    pub fn service_method_str(&self) -> &str {
+        str::from_utf8(&self.service_method).unwrap_or_default()
+    }
+
+    /// Returns the encoded metadata as it is on the wire.
+    pub fn raw_metadata(&self) -> &Bytes {
+        &self.metadata
+    }
+
+    /// Parses and returns all metadata.
+    pub fn metadata(&self) -> Result<Metadata> {
+        decode_metadata(&self.metadata)
+    }
+
+    /// Looks up a single metadata value without parsing the others.
+    pub fn get_metadata(&self, key: &str) -> Result<Option<&str>> {
+        for kv in MetadataIter::new(&self.metadata) {
+            let (k, v) = kv?;
+            if k == key {
+                return Ok(Some(v));
+            }
+        }
+        Ok(None)
+    }
+
+    pub fn set_metadata(&mut self, metadata: &Metadata) {
+        self.metadata = Bytes::from(encode_metadata(metadata));
+    }
+
+    /// Returns the payload after undoing the compression announced in the
+    /// header. Uncompressed payloads are returned without copying, encrypted
+    /// ones can't be decompressed before they are opened.
+    pub fn decompressed_payload(&self) -> Result<Bytes> {
+        if self.get_metadata(ENCRYPTION_KEY_ID)?.is_some() {
+            return Err(protocol_error("payload is encrypted"));
+        }
+        match self.get_compress_type() {
+            Some(crate::CompressType::CompressNone) => Ok(self.payload.clone()),
+            Some(ct) => {
+                let max_len = DecodeLimits::default().max_payload_size;
+                Ok(Bytes::from(decompress(ct, &self.payload, max_len)?))
+            }
+            None => Err(protocol_error("unknown compress type")),
+        }
+    }
+
+    /// Converts this frame into an owned `Message`, copying and decompressing
+    /// its parts.
+    pub fn to_message(&self) -> Result<Message> {
+        let mut msg = Message::new();
+        msg.header = self.header;
+        msg.service_path = self.service_path_str().to_owned();
+        msg.service_method = self.service_method_str().to_owned();
+        msg.metadata = self.metadata()?;
+        msg.payload = if msg.is_encrypted() {
+            self.payload.to_vec()
+        } else {
+            self.decompressed_payload()?.to_vec()
+        };
+        Ok(msg)
+    }
+
+    /// Decodes a frame from a blocking reader, rejecting frames which exceed
+    /// `limits`.
+    pub fn decode_with_limits<R>(&mut self, r: &mut R, limits: &DecodeLimits) -> Result<()>
+    where
+        R: Read + ?Sized,
+    {
+        let mut header = [0u8; 12];
+        r.read_exact(&mut header)?;
+
+        let mut buf = [0u8; 4];
+        r.read_exact(&mut buf[..])?;
+        let len = BigEndian::read_u32(&buf) as usize;
+        if len > limits.max_frame_size {
+            return Err(protocol_error("frame exceeds the size limit"));
+        }
+        let mut body = Vec::new();
+        r.take(len as u64).read_to_end(&mut body)?;
+        if body.len() != len {
+            return Err(Error::from(std::io::Error::from(
+                std::io::ErrorKind::UnexpectedEof,
+            )));
+        }
+
+        *self = Frame::from_parts_with_limits(header, Bytes::from(body), limits)?;
+        Ok(())
+    }
+
+    /// Length of the frame without the header and the length prefix.
+    pub(crate) fn body_len(&self) -> usize {
+        16 + self.service_path.len()
+            + self.service_method.len()
+            + self.metadata.len()
+            + self.payload.len()
+    }
+
+    /// Encodes the frame for vectored IO without copying the metadata or the
+    /// payload.
+    pub fn encode_vectored(&self) -> EncodedFrame<'_> {
+        let mut head = Vec::with_capacity(
+            12 + 4 + 4 + self.service_path.len() + 4 + self.service_method.len() + 4,
+        );
+        head.extend_from_slice(&self.header);
+        head.extend_from_slice(&write_len(self.body_len() as u32));
+        put_chunk(&mut head, &self.service_path);
+        put_chunk(&mut head, &self.service_method);
+        head.extend_from_slice(&write_len(self.metadata.len() as u32));
+
+        EncodedFrame {
+            head,
+            metadata: Cow::Borrowed(&self.metadata),
+            payload_len: write_len(self.payload.len() as u32),
+            payload: Cow::Borrowed(&self.payload),
+        }
+    }
+
+    /// Appends the encoded frame to `buf`.
+    pub fn encode_to(&self, buf: &mut BytesMut) {
+        buf.reserve(12 + 4 + self.body_len());
+        buf.extend_from_slice(&self.header);
+        buf.extend_from_slice(&write_len(self.body_len() as u32));
+        for part in [
+            &self.service_path,
+            &self.service_method,
+            &self.metadata,
+            &self.payload,
+        ]
+        .iter()
+        {
+            put_chunk(buf, part);
+        }
+    }
+}
+
+impl From<&Message> for Frame {
+    fn from(msg: &Message) -> Self {
+        let mut header = msg.header;
+        let payload = wire_payload(&mut header, &msg.metadata, &msg.payload).into_owned();
+        let metadata = wire_metadata(&msg.metadata, &header, &payload);
+        Frame {
+            header,
+            service_path: Bytes::copy_from_slice(msg.service_path.as_bytes()),
+            service_method: Bytes::copy_from_slice(msg.service_method.as_bytes()),
+            metadata: Bytes::from(encode_metadata(&metadata)),
+            payload: Bytes::from(payload),
+        }
+    }
+}
+
+impl RpcxMessage for Frame {
+    fn header(&self) -> &[u8; 12] {
+        &self.header
+    }
+    fn header_mut(&mut self) -> &mut [u8; 12] {
+        &mut self.header
+    }
+
+    fn decode<R>(&mut self, r: &mut R) -> Result<()>
+    where
+        R: Read + ?Sized,
+    {
+        self.decode_with_limits(r, &DecodeLimits::default())
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = BytesMut::new();
+        self.encode_to(&mut buf);
+        buf.to_vec()
+    }
+
+    fn get_error(&self) -> Option<String> {
+        match self.get_message_status_type() {
+            Some(MessageStatusType::Error) => self
+                .get_metadata(SERVICE_ERROR)
+                .ok()
+                .flatten()
+                .map(String::from),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CompressType;
+
+    const MSG_DATA: [u8; 114] = [
+        8, 0, 0, 16, 0, 0, 0, 0, 73, 150, 2, 210, 0, 0, 0, 98, 0, 0, 0, 5, 65, 114, 105, 116, 104,
+        0, 0, 0, 3, 65, 100, 100, 0, 0, 0, 48, 0, 0, 0, 4, 95, 95, 73, 68, 0, 0, 0, 36, 54, 98, 97,
+        55, 98, 56, 49, 48, 45, 57, 100, 97, 100, 45, 49, 49, 100, 49, 45, 56, 48, 98, 52, 45, 48,
+        48, 99, 48, 52, 102, 100, 52, 51, 48, 99, 57, 0, 0, 0, 26, 123, 10, 9, 9, 34, 65, 34, 58,
+        32, 49, 44, 10, 9, 9, 34, 66, 34, 58, 32, 50, 44, 10, 9, 125, 10, 9,
+    ];
+
+    #[test]
+    fn decode_without_copy() {
+        let data = Bytes::from_static(&MSG_DATA);
+        let mut header = [0u8; 12];
+        header.copy_from_slice(&data[..12]);
+        let body = data.slice(16..);
+        let frame = Frame::from_parts(header, body.clone()).unwrap();
+
+        assert_eq!(1234567890, frame.get_seq());
+        assert_eq!("Arith", frame.service_path_str());
+        assert_eq!("Add", frame.service_method_str());
+        assert_eq!(
+            Some("6ba7b810-9dad-11d1-80b4-00c04fd430c9"),
+            frame.get_metadata("__ID").unwrap()
+        );
+
+        // the payload points into the original buffer
+        let payload_start = frame.payload.as_ptr() as usize - body.as_ptr() as usize;
+        assert_eq!(&body[payload_start..], &frame.payload[..]);
+        assert_eq!(
+            data.as_ptr() as usize + 16 + payload_start,
+            frame.payload.as_ptr() as usize
+        );
+    }
+
+    #[test]
+    fn encode_round_trip() {
+        let mut frame = Frame::new();
+        let mut data = &MSG_DATA[..];
+        frame.decode(&mut data).unwrap();
+
+        assert_eq!(&MSG_DATA[..], &frame.encode()[..]);
+    }
+
+    #[test]
+    fn convert_to_message() {
+        let mut msg = Message::new();
+        msg.set_compress_type(CompressType::Gzip).unwrap();
+        msg.service_path = "Arith".to_owned();
+        msg.service_method = "Mul".to_owned();
+        msg.metadata.insert("key".to_owned(), "value".to_owned());
+        msg.payload = b"{\"A\":1,\"B\":2}".to_vec();
+
+        let frame = Frame::from(&msg);
+        assert_ne!(&msg.payload[..], &frame.payload[..]);
+        assert_eq!(&msg.payload[..], &frame.decompressed_payload().unwrap()[..]);
+
+        let decoded = frame.to_message().unwrap();
+        assert_eq!(msg.header, decoded.header);
+        assert_eq!("Mul", decoded.service_method);
+        assert_eq!("value", decoded.metadata["key"]);
+        assert_eq!(msg.payload, decoded.payload);
+    }
+
+    #[test]
+    fn encode_vectored() {
+        let data = Bytes::from_static(&MSG_DATA);
+        let mut header = [0u8; 12];
+        header.copy_from_slice(&data[..12]);
+        let frame = Frame::from_parts(header, data.slice(16..)).unwrap();
+
+        let encoded = frame.encode_vectored();
+        assert_eq!(frame.payload.as_ptr(), encoded.io_slices()[3].as_ptr());
+        let mut buf = Vec::new();
+        encoded.write_to(&mut buf).unwrap();
+        assert_eq!(&MSG_DATA[..], &buf[..]);
+    }
+}
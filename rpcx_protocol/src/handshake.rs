@@ -0,0 +1,120 @@
+use crate::{
+    Metadata, ACCEPT_FEATURES_KEY, ACCEPT_VERSION_KEY, CANCEL_FEATURE, FEATURES_KEY,
+    FRAGMENT_FEATURE, PROTOCOL_VERSION, VERSION_KEY,
+};
+
+/// the protocol version and optional features of a connection.
+///
+/// A client offers what it supports in the metadata of its first heartbeat
+/// and the server answers with what both sides support. The answer uses
+/// other keys than the offer, so a Go rpcx server, which echoes heartbeats
+/// as they are, is read as version 0 without features.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Handshake {
+    pub version: u8,
+    pub features: Vec<String>,
+}
+
+impl Handshake {
+    pub fn new(version: u8, features: Vec<String>) -> Self {
+        Handshake { version, features }
+    }
+
+    /// Returns the newest version and the features this crate supports.
+    pub fn supported() -> Self {
+        Handshake::new(
+            PROTOCOL_VERSION,
+            vec![FRAGMENT_FEATURE.to_owned(), CANCEL_FEATURE.to_owned()],
+        )
+    }
+
+    pub fn has_feature(&self, feature: &str) -> bool {
+        self.features.iter().any(|f| f == feature)
+    }
+
+    /// Agrees on the lower of both versions and the features both support.
+    pub fn negotiate(&self, offer: &Handshake) -> Handshake {
+        Handshake {
+            version: self.version.min(offer.version),
+            features: offer
+                .features
+                .iter()
+                .filter(|f| self.has_feature(f))
+                .cloned()
+                .collect(),
+        }
+    }
+
+    pub fn write_offer(&self, metadata: &mut Metadata) {
+        self.write(metadata, VERSION_KEY, FEATURES_KEY);
+    }
+
+    /// Reads a client's offer, `None` if the heartbeat carries none.
+    pub fn read_offer(metadata: &Metadata) -> Option<Handshake> {
+        Handshake::read(metadata, VERSION_KEY, FEATURES_KEY)
+    }
+
+    pub fn write_accept(&self, metadata: &mut Metadata) {
+        self.write(metadata, ACCEPT_VERSION_KEY, ACCEPT_FEATURES_KEY);
+    }
+
+    /// Reads the server's answer, falling back to version 0 without features
+    /// for servers which don't negotiate.
+    pub fn read_accept(metadata: &Metadata) -> Handshake {
+        Handshake::read(metadata, ACCEPT_VERSION_KEY, ACCEPT_FEATURES_KEY).unwrap_or_default()
+    }
+
+    fn write(&self, metadata: &mut Metadata, version_key: &str, features_key: &str) {
+        metadata.insert(version_key.to_owned(), self.version.to_string());
+        metadata.insert(features_key.to_owned(), self.features.join(","));
+    }
+
+    fn read(metadata: &Metadata, version_key: &str, features_key: &str) -> Option<Handshake> {
+        let version = metadata.get(version_key)?.parse().ok()?;
+        let features = metadata
+            .get(features_key)
+            .map(|s| {
+                s.split(',')
+                    .filter(|f| !f.is_empty())
+                    .map(String::from)
+                    .collect()
+            })
+            .unwrap_or_default();
+        Some(Handshake { version, features })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negotiate() {
+        let client = Handshake::new(3, vec!["crc32c".to_owned(), "fragment".to_owned()]);
+        let server = Handshake::new(1, vec!["fragment".to_owned(), "encrypt".to_owned()]);
+
+        let mut req = Metadata::new();
+        client.write_offer(&mut req);
+        let offer = Handshake::read_offer(&req).unwrap();
+        assert_eq!(client, offer);
+
+        let mut reply = Metadata::new();
+        server.negotiate(&offer).write_accept(&mut reply);
+        let agreed = Handshake::read_accept(&reply);
+        assert_eq!(1, agreed.version);
+        assert_eq!(vec!["fragment".to_owned()], agreed.features);
+        assert!(agreed.has_feature("fragment"));
+        assert!(!agreed.has_feature("crc32c"));
+    }
+
+    #[test]
+    fn legacy_peers() {
+        // Go rpcx servers echo the heartbeat with the offer in it.
+        let mut echo = Metadata::new();
+        Handshake::new(1, vec!["crc32c".to_owned()]).write_offer(&mut echo);
+        assert_eq!(Handshake::default(), Handshake::read_accept(&echo));
+
+        // and clients which don't negotiate send no offer at all.
+        assert_eq!(None, Handshake::read_offer(&Metadata::new()));
+    }
+}
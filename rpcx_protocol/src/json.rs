@@ -0,0 +1,278 @@
+use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue;
+
+use std::collections::BTreeMap;
+
+use crate::{
+    message::protocol_error, CompressType, Message, MessageStatusType, MessageType, Result,
+    RpcxMessage, SerializeType,
+};
+
+/// a readable form of a `Message`, for logs and test fixtures.
+///
+/// The header flags are named and the payload is shown as JSON when it
+/// holds JSON or MsgPack, so a frame can be read and edited by hand. The
+/// conversion is lossless: `to_message` gives back an equal message.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JsonMessage {
+    pub version: u8,
+    pub message_type: MessageType,
+    pub heartbeat: bool,
+    pub oneway: bool,
+    pub compress_type: CompressType,
+    pub status: MessageStatusType,
+    pub serialize_type: SerializeType,
+    /// the low four bits of the serialize byte, unused by rpcx but kept so
+    /// the header survives the conversion.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub reserved: u8,
+    pub seq: u64,
+    pub service_path: String,
+    pub service_method: String,
+    /// sorted by key, so the output is stable.
+    pub metadata: BTreeMap<String, String>,
+    pub payload: JsonPayload,
+}
+
+/// the payload of a `JsonMessage`, after decompression.
+///
+/// A payload is only shown as `json` or `msgpack` if it is encoded back to
+/// the same bytes, everything else is kept as `base64`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JsonPayload {
+    /// a JSON payload, kept as it was written.
+    Json(Box<RawValue>),
+    /// a MsgPack payload converted to JSON.
+    MsgPack(serde_json::Value),
+    Base64(String),
+}
+
+impl JsonPayload {
+    pub fn new(st: SerializeType, payload: &[u8]) -> Self {
+        let readable = match st {
+            SerializeType::JSON => json_payload(payload),
+            SerializeType::MsgPack => msgpack_payload(payload),
+            _ => None,
+        };
+        readable.unwrap_or_else(|| JsonPayload::Base64(base64::encode(payload)))
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        match self {
+            JsonPayload::Json(raw) => Ok(raw.get().as_bytes().to_vec()),
+            JsonPayload::MsgPack(value) => {
+                rmp_serde::to_vec(value).map_err(|_| protocol_error("payload is not valid MsgPack"))
+            }
+            JsonPayload::Base64(data) => {
+                base64::decode(data).map_err(|_| protocol_error("payload is not valid base64"))
+            }
+        }
+    }
+}
+
+fn json_payload(payload: &[u8]) -> Option<JsonPayload> {
+    let text = std::str::from_utf8(payload).ok()?;
+    let raw = serde_json::from_str::<Box<RawValue>>(text).ok()?;
+    // surrounding whitespace isn't kept by `RawValue`
+    if raw.get() != text {
+        return None;
+    }
+    Some(JsonPayload::Json(raw))
+}
+
+fn msgpack_payload(payload: &[u8]) -> Option<JsonPayload> {
+    let value: serde_json::Value = rmp_serde::from_slice(payload).ok()?;
+    // integer widths, floats and key order may not survive the conversion
+    if rmp_serde::to_vec(&value).ok()? != payload {
+        return None;
+    }
+    Some(JsonPayload::MsgPack(value))
+}
+
+fn is_zero(n: &u8) -> bool {
+    *n == 0
+}
+
+impl JsonMessage {
+    /// Converts a message, failing if its header has an unknown compress or
+    /// status type.
+    pub fn from_message(msg: &Message) -> Result<Self> {
+        let serialize_type = msg
+            .get_serialize_type()
+            .ok_or_else(|| protocol_error("unknown serialize type"))?;
+        Ok(JsonMessage {
+            version: msg.get_version(),
+            message_type: msg
+                .get_message_type()
+                .ok_or_else(|| protocol_error("unknown message type"))?,
+            heartbeat: msg.is_heartbeat(),
+            oneway: msg.is_oneway(),
+            compress_type: msg
+                .get_compress_type()
+                .ok_or_else(|| protocol_error("unknown compress type"))?,
+            status: msg
+                .get_message_status_type()
+                .ok_or_else(|| protocol_error("unknown message status type"))?,
+            serialize_type,
+            reserved: msg.header[3] & 0x0F,
+            seq: msg.get_seq(),
+            service_path: msg.service_path.clone(),
+            service_method: msg.service_method.clone(),
+            metadata: msg
+                .metadata
+                .iter()
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect(),
+            payload: JsonPayload::new(serialize_type, &msg.payload),
+        })
+    }
+
+    pub fn to_message(&self) -> Result<Message> {
+        if self.reserved > 0x0F {
+            return Err(protocol_error("reserved bits exceed four bits"));
+        }
+        let mut msg = Message::new();
+        msg.set_version(self.version);
+        msg.set_message_type(self.message_type);
+        msg.set_heartbeat(self.heartbeat);
+        msg.set_oneway(self.oneway);
+        msg.set_compress_type(self.compress_type)
+            .map_err(|_| protocol_error("invalid compress type"))?;
+        msg.set_message_status_type(self.status);
+        msg.set_serialize_type(self.serialize_type)
+            .map_err(|_| protocol_error("invalid serialize type"))?;
+        msg.header[3] |= self.reserved;
+        msg.set_seq(self.seq);
+        msg.service_path = self.service_path.clone();
+        msg.service_method = self.service_method.clone();
+        msg.metadata = self
+            .metadata
+            .iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+        msg.payload = self.payload.to_bytes()?;
+        Ok(msg)
+    }
+}
+
+impl Message {
+    /// Returns the message as pretty-printed JSON, see `JsonMessage`.
+    pub fn to_json(&self) -> Result<String> {
+        let json = JsonMessage::from_message(self)?;
+        Ok(serde_json::to_string_pretty(&json)?)
+    }
+
+    /// Reads a message written by `to_json`.
+    pub fn from_json(s: &str) -> Result<Message> {
+        let json: JsonMessage = serde_json::from_str(s)?;
+        json.to_message()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MessageBuilder, SERVICE_ERROR};
+
+    fn message(st: SerializeType, payload: &[u8]) -> Message {
+        MessageBuilder::new()
+            .compress_type(CompressType::Gzip)
+            .serialize_type(st)
+            .seq(1234567890)
+            .service_path("Arith")
+            .service_method("Mul")
+            .meta("__ID", "6ba7b810-9dad-11d1-80b4-00c04fd430c9")
+            .payload(payload.to_vec())
+            .build()
+            .unwrap()
+    }
+
+    fn round_trip(msg: &Message) -> (serde_json::Value, Message) {
+        let json = msg.to_json().unwrap();
+        let back = Message::from_json(&json).unwrap();
+        assert_eq!(msg.header, back.header);
+        assert_eq!(msg.service_path, back.service_path);
+        assert_eq!(msg.service_method, back.service_method);
+        assert_eq!(msg.metadata, back.metadata);
+        assert_eq!(msg.payload, back.payload);
+        (serde_json::from_str(&json).unwrap(), back)
+    }
+
+    #[test]
+    fn reserved_bits() {
+        let mut msg = message(SerializeType::JSON, br#"{"A":1}"#);
+        msg.header[3] |= 0x0A;
+        let (json, back) = round_trip(&msg);
+        assert_eq!(10, json["reserved"]);
+        assert_eq!(0x1A, back.header[3]);
+
+        let (json, _) = round_trip(&message(SerializeType::JSON, br#"{"A":1}"#));
+        assert!(json.get("reserved").is_none());
+
+        let mut json = JsonMessage::from_message(&msg).unwrap();
+        json.reserved = 0x10;
+        assert!(json.to_message().is_err());
+    }
+
+    #[test]
+    fn json_payload() {
+        // Go writes indented JSON, which is kept as it is
+        let payload = b"{\n\t\t\"A\": 1,\n\t\t\"B\": 2\n\t}";
+        let (json, _) = round_trip(&message(SerializeType::JSON, payload));
+        assert_eq!("Request", json["message_type"]);
+        assert_eq!("Gzip", json["compress_type"]);
+        assert_eq!("JSON", json["serialize_type"]);
+        assert_eq!(1234567890, json["seq"]);
+        assert_eq!(
+            "6ba7b810-9dad-11d1-80b4-00c04fd430c9",
+            json["metadata"]["__ID"]
+        );
+        assert_eq!(2, json["payload"]["json"]["B"]);
+
+        // but not surrounding whitespace
+        let (json, _) = round_trip(&message(SerializeType::JSON, b"{\"A\":1}\n"));
+        assert_eq!("eyJBIjoxfQo=", json["payload"]["base64"]);
+    }
+
+    #[test]
+    fn msgpack_payload() {
+        let payload = [0x82, 0xa1, 0x41, 0x0a, 0xa1, 0x42, 0x14];
+        let (json, _) = round_trip(&message(SerializeType::MsgPack, &payload));
+        assert_eq!(10, json["payload"]["msgpack"]["A"]);
+
+        // a u16 holding a small number isn't encoded the same way again
+        let payload = [0x81, 0xa1, 0x41, 0xcd, 0x00, 0x0a];
+        let (json, _) = round_trip(&message(SerializeType::MsgPack, &payload));
+        assert_eq!("gaFBzQAK", json["payload"]["base64"]);
+    }
+
+    #[test]
+    fn base64_payload() {
+        let (json, _) = round_trip(&message(SerializeType::Protobuf, &[0x08, 0x0a, 0x10, 0x14]));
+        assert_eq!("CAoQFA==", json["payload"]["base64"]);
+
+        let mut msg = message(SerializeType::Custom(9), b"");
+        msg.set_message_type(MessageType::Response);
+        msg.set_message_status_type(MessageStatusType::Error);
+        msg.metadata
+            .insert(SERVICE_ERROR.to_owned(), "service not found".to_owned());
+        let (json, back) = round_trip(&msg);
+        assert_eq!(9, json["serialize_type"]["Custom"]);
+        assert_eq!("Error", json["status"]);
+        assert_eq!("", json["payload"]["base64"]);
+        assert_eq!(Some("service not found".to_owned()), back.get_error());
+    }
+
+    #[test]
+    fn invalid_json() {
+        let json = JsonMessage::from_message(&message(SerializeType::JSON, b"{}")).unwrap();
+        let mut value = serde_json::to_value(&json).unwrap();
+        value["compress_type"] = serde_json::json!({ "Custom": 2 });
+        assert!(Message::from_json(&value.to_string()).is_err());
+
+        value["compress_type"] = serde_json::json!("Gzip");
+        value["payload"] = serde_json::json!({ "base64": "not base64!" });
+        assert!(Message::from_json(&value.to_string()).is_err());
+    }
+}
@@ -1,7 +1,33 @@
 pub mod call;
+pub mod codec;
+pub mod compressor;
+pub mod encoded;
+pub mod encryption;
 pub mod error;
+pub mod fragment;
+pub mod frame;
+pub mod handshake;
+pub mod json;
 pub mod message;
+pub mod meta;
+pub mod serializer;
+pub mod server_error;
+#[cfg(feature = "thrift")]
+pub mod thrift_param;
 
 pub use call::*;
+pub use codec::*;
+pub use compressor::*;
+pub use encoded::*;
+pub use encryption::*;
 pub use error::*;
+pub use fragment::*;
+pub use frame::*;
+pub use handshake::*;
+pub use json::*;
 pub use message::*;
+pub use meta::*;
+pub use serializer::*;
+pub use server_error::*;
+#[cfg(feature = "thrift")]
+pub use thrift_param::*;
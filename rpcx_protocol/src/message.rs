@@ -1,317 +1,526 @@
 use byteorder::{BigEndian, ByteOrder};
+use bytes::{BufMut, BytesMut};
 use enum_primitive_derive::Primitive;
-use flate2::{read::GzDecoder, write::GzEncoder, Compression};
 use num_traits::{FromPrimitive, ToPrimitive};
+use serde::{Deserialize, Serialize};
 use strum_macros::{Display, EnumIter, EnumString};
 
-use std::{
-    cell::RefCell,
-    collections::hash_map::HashMap,
-    io::{Read, Write},
+use std::{borrow::Cow, collections::hash_map::HashMap, io::Read, ops::Range};
+
+use crate::{
+    get_compressor, EncodedFrame, Error, ErrorKind, MetadataExt, Result, ServerError, CHECKSUM_KEY,
+    ENCRYPTION_KEY_ID, REPLY_CHECKSUM_KEY,
 };
 
-use crate::{Error, Result};
+pub(crate) const MAGIC_NUMBER: u8 = 0x08;
+/// the newest protocol version this crate reads and writes. Go rpcx writes
+/// version 0, frames with a newer version are rejected while decoding.
+pub const PROTOCOL_VERSION: u8 = 0;
+
+/// limits which are checked while decoding a frame.
+///
+/// Frames which exceed any limit are rejected with `ErrorKind::Protocol`
+/// before the oversized part is buffered or inflated.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct DecodeLimits {
+    /// the maximum length of everything after the header and the length prefix.
+    pub max_frame_size: usize,
+    /// the maximum length of the encoded metadata.
+    pub max_metadata_size: usize,
+    /// the maximum length of the payload, both on the wire and decompressed.
+    pub max_payload_size: usize,
+}
 
-const MAGIC_NUMBER: u8 = 0x08;
-pub const SERVICE_ERROR: &str = "__rpcx_error__";
+impl Default for DecodeLimits {
+    fn default() -> Self {
+        DecodeLimits {
+            max_frame_size: u32::MAX as usize,
+            max_metadata_size: u32::MAX as usize,
+            max_payload_size: u32::MAX as usize,
+        }
+    }
+}
 
-#[derive(Debug, Copy, Clone, Display, PartialEq, EnumIter, EnumString, Primitive)]
+#[derive(
+    Debug, Copy, Clone, Display, PartialEq, EnumIter, EnumString, Primitive, Serialize, Deserialize,
+)]
 pub enum MessageType {
     Request = 0,
     Response = 1,
 }
 
-#[derive(Debug, Copy, Clone, Display, PartialEq, EnumIter, EnumString, Primitive)]
+#[derive(
+    Debug, Copy, Clone, Display, PartialEq, EnumIter, EnumString, Primitive, Serialize, Deserialize,
+)]
 pub enum MessageStatusType {
     Normal = 0,
     Error = 1,
 }
 
-#[derive(Debug, Copy, Clone, Display, PartialEq, EnumIter, EnumString, Primitive)]
+/// the compression of the payload, three bits in the header.
+///
+/// Payloads are compressed by the `Compressor` registered for the type.
+/// Gzip is always available, Snappy, Zstd and Lz4 when the crate is built
+/// with the `snappy`, `zstd` or `lz4` feature. The ids 5 to 7 are free for
+/// user compressors registered as `Custom`.
+#[derive(Debug, Copy, Clone, Display, PartialEq, EnumIter, EnumString, Serialize, Deserialize)]
 pub enum CompressType {
-    CompressNone = 0,
-    Gzip = 1,
+    CompressNone,
+    Gzip,
+    Snappy,
+    Zstd,
+    Lz4,
+    Custom(u8),
 }
 
-#[derive(Debug, Copy, Clone, Display, PartialEq, EnumIter, EnumString, Primitive)]
-pub enum SerializeType {
-    SerializeNone = 0,
-    JSON = 1,
-    Protobuf = 2,
-    MsgPack = 3,
-    Thrift = 4,
+impl FromPrimitive for CompressType {
+    fn from_i64(n: i64) -> Option<Self> {
+        if n < 0 {
+            return None;
+        }
+        Self::from_u64(n as u64)
+    }
+
+    fn from_u64(n: u64) -> Option<Self> {
+        match n {
+            0 => Some(CompressType::CompressNone),
+            1 => Some(CompressType::Gzip),
+            2 => Some(CompressType::Snappy),
+            3 => Some(CompressType::Zstd),
+            4 => Some(CompressType::Lz4),
+            5..=7 => Some(CompressType::Custom(n as u8)),
+            _ => None,
+        }
+    }
 }
 
-/// define the rpcx message interface.
-pub trait RpcxMessage {
-    fn check_magic_number(&self) -> bool;
-    fn get_version(&self) -> u8;
-    fn set_version(&mut self, v: u8);
-    fn get_message_type(&self) -> Option<MessageType>;
-    fn set_message_type(&mut self, mt: MessageType);
-    fn is_heartbeat(&self) -> bool;
-    fn set_heartbeat(&mut self, b: bool);
-    fn is_oneway(&self) -> bool;
-    fn set_oneway(&mut self, b: bool);
-    fn get_compress_type(&self) -> Option<CompressType>;
-    fn set_compress_type(&mut self, ct: CompressType);
-    fn get_message_status_type(&self) -> Option<MessageStatusType>;
-    fn set_message_status_type(&mut self, mst: MessageStatusType);
-    fn get_serialize_type(&self) -> Option<SerializeType>;
-    fn set_serialize_type(&mut self, st: SerializeType);
-    fn get_seq(&self) -> u64;
-    fn set_seq(&mut self, seq: u64);
-    fn decode<R: ?Sized>(&mut self, r: &mut R) -> Result<()>
-    where
-        R: Read;
-    fn encode(&self) -> Vec<u8>;
+impl ToPrimitive for CompressType {
+    fn to_i64(&self) -> Option<i64> {
+        self.to_u64().map(|n| n as i64)
+    }
 
-    fn get_error(&self) -> Option<String>;
+    fn to_u64(&self) -> Option<u64> {
+        match *self {
+            CompressType::CompressNone => Some(0),
+            CompressType::Gzip => Some(1),
+            CompressType::Snappy => Some(2),
+            CompressType::Zstd => Some(3),
+            CompressType::Lz4 => Some(4),
+            CompressType::Custom(id @ 5..=7) => Some(u64::from(id)),
+            CompressType::Custom(_) => None,
+        }
+    }
 }
 
-pub type Metadata = HashMap<String, String>;
-
-/// a commmon struct for request and response.
-#[derive(Debug, Default)]
-pub struct Message {
-    pub header: [u8; 12],
-    pub service_path: String,
-    pub service_method: String,
-    pub metadata: RefCell<Metadata>,
-    pub payload: Vec<u8>,
+/// the serialization of the payload, four bits in the header.
+///
+/// Types deriving `RpcxParam` are serialized by the `Serializer` registered
+/// for the type, JSON and MsgPack are always available. The ids 5 to 15 are
+/// free for user serializers registered as `Custom`.
+#[derive(Debug, Copy, Clone, Display, PartialEq, EnumIter, EnumString, Serialize, Deserialize)]
+pub enum SerializeType {
+    SerializeNone,
+    JSON,
+    Protobuf,
+    MsgPack,
+    Thrift,
+    Custom(u8),
 }
-impl Message {
-    /// Creates a new `Message`
-    pub fn new() -> Self {
-        let mut msg: Message = Default::default();
-        msg.header = [0u8; 12];
-        msg.header[0] = MAGIC_NUMBER;
-        msg.metadata = RefCell::new(HashMap::new());
-        msg
+
+impl FromPrimitive for SerializeType {
+    fn from_i64(n: i64) -> Option<Self> {
+        if n < 0 {
+            return None;
+        }
+        Self::from_u64(n as u64)
     }
 
-    pub fn get_reply(&self) -> Result<Self> {
-        let mut reply = Message::new();
-        reply.set_version(self.get_version());
-        reply.set_compress_type(self.get_compress_type().unwrap());
-        reply.set_message_status_type(MessageStatusType::Normal);
-        reply.set_message_type(MessageType::Response);
-        reply.set_serialize_type(self.get_serialize_type().unwrap());
-        reply.set_seq(self.get_seq());
-        reply.service_path = self.service_path.clone();
-        reply.service_method = self.service_method.clone();
+    fn from_u64(n: u64) -> Option<Self> {
+        match n {
+            0 => Some(SerializeType::SerializeNone),
+            1 => Some(SerializeType::JSON),
+            2 => Some(SerializeType::Protobuf),
+            3 => Some(SerializeType::MsgPack),
+            4 => Some(SerializeType::Thrift),
+            5..=15 => Some(SerializeType::Custom(n as u8)),
+            _ => None,
+        }
+    }
+}
 
-        Ok(reply)
+impl ToPrimitive for SerializeType {
+    fn to_i64(&self) -> Option<i64> {
+        self.to_u64().map(|n| n as i64)
+    }
+
+    fn to_u64(&self) -> Option<u64> {
+        match *self {
+            SerializeType::SerializeNone => Some(0),
+            SerializeType::JSON => Some(1),
+            SerializeType::Protobuf => Some(2),
+            SerializeType::MsgPack => Some(3),
+            SerializeType::Thrift => Some(4),
+            SerializeType::Custom(id @ 5..=15) => Some(u64::from(id)),
+            SerializeType::Custom(_) => None,
+        }
     }
 }
 
-impl RpcxMessage for Message {
+/// define the rpcx message interface.
+///
+/// All header accessors are provided on top of `header` and `header_mut`.
+pub trait RpcxMessage {
+    fn header(&self) -> &[u8; 12];
+    fn header_mut(&mut self) -> &mut [u8; 12];
+
     fn check_magic_number(&self) -> bool {
-        self.header[0] == MAGIC_NUMBER
+        self.header()[0] == MAGIC_NUMBER
     }
-
     fn get_version(&self) -> u8 {
-        self.header[1]
+        self.header()[1]
     }
     fn set_version(&mut self, v: u8) {
-        self.header[1] = v;
+        self.header_mut()[1] = v;
     }
-
     fn get_message_type(&self) -> Option<MessageType> {
-        MessageType::from_u8((self.header[2] & 0x80) >> 7 as u8)
+        MessageType::from_u8((self.header()[2] & 0x80) >> 7 as u8)
     }
     fn set_message_type(&mut self, mt: MessageType) {
-        self.header[2] |= mt.to_u8().unwrap() << 7;
+        let header = self.header_mut();
+        header[2] = (header[2] & !0x80) | (mt.to_u8().unwrap() << 7);
     }
     fn is_heartbeat(&self) -> bool {
-        self.header[2] & 0x40 == 0x40
+        self.header()[2] & 0x40 == 0x40
     }
     fn set_heartbeat(&mut self, b: bool) {
         if b {
-            self.header[2] |= 0x40;
+            self.header_mut()[2] |= 0x40;
         } else {
-            self.header[2] &= !0x40;
+            self.header_mut()[2] &= !0x40;
         }
     }
     fn is_oneway(&self) -> bool {
-        self.header[2] & 0x20 == 0x20
+        self.header()[2] & 0x20 == 0x20
     }
     fn set_oneway(&mut self, b: bool) {
         if b {
-            self.header[2] |= 0x20;
+            self.header_mut()[2] |= 0x20;
         } else {
-            self.header[2] &= !0x20;
+            self.header_mut()[2] &= !0x20;
         }
     }
     fn get_compress_type(&self) -> Option<CompressType> {
-        CompressType::from_u8((self.header[2] & 0x1C) >> 2)
+        CompressType::from_u8((self.header()[2] & 0x1C) >> 2)
     }
-    fn set_compress_type(&mut self, ct: CompressType) {
-        self.header[2] = (self.header[2] & !0x1C) | (ct.to_u8().unwrap() << 2 & 0x1C);
+    /// Fails for a `Custom` id which does not fit the three bits.
+    fn set_compress_type(&mut self, ct: CompressType) -> Result<()> {
+        let id = ct
+            .to_u8()
+            .ok_or_else(|| Error::new(ErrorKind::Other, "invalid compress type"))?;
+        let header = self.header_mut();
+        header[2] = (header[2] & !0x1C) | (id << 2);
+        Ok(())
     }
     fn get_message_status_type(&self) -> Option<MessageStatusType> {
-        MessageStatusType::from_u8(self.header[2] & 0x03)
+        MessageStatusType::from_u8(self.header()[2] & 0x03)
     }
     fn set_message_status_type(&mut self, mst: MessageStatusType) {
-        self.header[2] = (self.header[2] & !0x03) | (mst.to_u8().unwrap() & 0x03);
+        let header = self.header_mut();
+        header[2] = (header[2] & !0x03) | (mst.to_u8().unwrap() & 0x03);
     }
     fn get_serialize_type(&self) -> Option<SerializeType> {
-        SerializeType::from_u8((self.header[3] & 0xF0) >> 4)
+        SerializeType::from_u8((self.header()[3] & 0xF0) >> 4)
     }
-    fn set_serialize_type(&mut self, st: SerializeType) {
-        self.header[3] = (self.header[3] & !0xF0) | (st.to_u8().unwrap() << 4)
+    /// Fails for a `Custom` id which does not fit the four bits.
+    fn set_serialize_type(&mut self, st: SerializeType) -> Result<()> {
+        let id = st
+            .to_u8()
+            .ok_or_else(|| Error::new(ErrorKind::Other, "invalid serialize type"))?;
+        let header = self.header_mut();
+        header[3] = (header[3] & !0xF0) | (id << 4);
+        Ok(())
     }
     fn get_seq(&self) -> u64 {
-        u64_from_slice(&(self.header[4..]))
+        u64_from_slice(&(self.header()[4..]))
     }
     fn set_seq(&mut self, seq: u64) {
-        u64_to_slice(seq, &mut self.header[4..]);
+        u64_to_slice(seq, &mut self.header_mut()[4..]);
     }
 
     fn decode<R: ?Sized>(&mut self, r: &mut R) -> Result<()>
     where
-        R: Read,
-    {
-        r.read_exact(&mut self.header)?;
+        R: Read;
+    fn encode(&self) -> Vec<u8>;
 
-        let mut buf = [0u8; 4];
-        r.read(&mut buf[..]).map(|_| {})?;
-        let len = BigEndian::read_u32(&buf); //length of all expect header
-        let mut buf = vec![0u8; len as usize];
-        r.read(&mut buf[..]).map(|_| ())?;
-
-        let mut start = 0;
-        // read service_path
-        let len = read_len(&buf[start..(start + 4)]) as usize;
-        let service_path = read_str(&buf[(start + 4)..(start + 4 + len)])?;
-        self.service_path = service_path;
-        start = start + 4 + len;
-        // read service_method
-        let len = read_len(&buf[start..(start + 4)]) as usize;
-        let service_method = read_str(&buf[(start + 4)..(start + 4 + len)])?;
-        self.service_method = service_method;
-
-        start = start + 4 + len;
-        //metadata
-        let len = read_len(&buf[start..(start + 4)]) as usize;
-        let metadata_bytes = &buf[(start + 4)..(start + 4 + len)];
-        let mut meta_start = 0;
-        while meta_start < len {
-            let sl = read_len(&metadata_bytes[meta_start..(meta_start + 4)]) as usize;
-            let key = read_str(&metadata_bytes[(meta_start + 4)..(meta_start + 4 + sl)])?;
-            meta_start = meta_start + 4 + sl;
-            if meta_start < len {
-                let value_len = read_len(&metadata_bytes[meta_start..(meta_start + 4)]) as usize;
-                let value =
-                    read_str(&metadata_bytes[(meta_start + 4)..(meta_start + 4 + value_len)])?;
-                self.metadata.borrow_mut().insert(key, value);
-                meta_start = meta_start + 4 + value_len;
+    fn get_error(&self) -> Option<String>;
+}
+
+pub type Metadata = HashMap<String, String>;
+
+/// a commmon struct for request and response.
+#[derive(Debug, Default)]
+pub struct Message {
+    pub header: [u8; 12],
+    pub service_path: String,
+    pub service_method: String,
+    pub metadata: Metadata,
+    pub payload: Vec<u8>,
+}
+impl Message {
+    /// Creates a new `Message`
+    pub fn new() -> Self {
+        let mut msg: Message = Default::default();
+        msg.header = [0u8; 12];
+        msg.header[0] = MAGIC_NUMBER;
+        msg.header[1] = PROTOCOL_VERSION;
+        msg
+    }
+
+    /// Creates a `MessageBuilder` for a request.
+    pub fn builder() -> MessageBuilder {
+        MessageBuilder::new()
+    }
+
+    pub fn get_reply(&self) -> Result<Self> {
+        MessageBuilder::new()
+            .version(self.get_version())
+            .message_type(MessageType::Response)
+            .heartbeat(self.is_heartbeat())
+            .compress_type(
+                self.get_compress_type()
+                    .unwrap_or(CompressType::CompressNone),
+            )
+            .serialize_type(
+                self.get_serialize_type()
+                    .unwrap_or(SerializeType::SerializeNone),
+            )
+            .seq(self.get_seq())
+            .service_path(self.service_path.clone())
+            .service_method(self.service_method.clone())
+            .checksum(self.has_checksum())
+            .build()
+    }
+
+    /// Marks a reply as failed with `err`, keeping its kind and, if it has
+    /// one, its `ServerError` for Rust clients.
+    pub fn set_reply_error(&mut self, err: &Error) {
+        self.set_message_status_type(MessageStatusType::Error);
+        match err.server_error() {
+            Some(server_error) => server_error.write_to(&mut self.metadata),
+            None => self.metadata.set_error(&err.to_string()),
+        }
+        if err.kind() != ErrorKind::Other {
+            self.metadata.set_error_kind(err.kind());
+        }
+    }
+
+    /// Returns the error of a reply whose status is `MessageStatusType::Error`,
+    /// see `Error::server_error`.
+    ///
+    /// Go rpcx sends no kind, its "can't find service" and "can't find
+    /// method" errors are read as `ErrorKind::NotFound`, others as
+    /// `ErrorKind::Other`.
+    pub fn reply_error(&self) -> Option<Error> {
+        if self.get_message_status_type() != Some(MessageStatusType::Error) {
+            return None;
+        }
+        let err = ServerError::read_from(&self.metadata);
+        let kind = self.metadata.error_kind().unwrap_or_else(|| {
+            if err.message.starts_with("rpcx: can't find ") {
+                ErrorKind::NotFound
             } else {
-                self.metadata.borrow_mut().insert(key, String::new());
-                break;
+                ErrorKind::Other
             }
+        });
+        Some(Error::new(kind, err))
+    }
+
+    /// Sends a CRC32C checksum of the payload with the message, which the
+    /// receiver verifies. Peers which don't know the checksum ignore it.
+    ///
+    /// The checksum is kept in the metadata and computed in `encode`, replies
+    /// made by `get_reply` carry one if the request did.
+    pub fn set_checksum(&mut self, enabled: bool) {
+        if enabled {
+            self.metadata.entry(CHECKSUM_KEY.to_owned()).or_default();
+        } else {
+            self.metadata.remove(CHECKSUM_KEY);
+            self.metadata.remove(REPLY_CHECKSUM_KEY);
         }
-        start = start + 4 + len;
-        // payload
-        let len = read_len(&buf[start..start + 4]) as usize;
-        let payload = &buf[start + 4..];
-        if len != payload.len() {
-            return Err(Error::from("invalid payload length"));
+    }
+
+    pub fn has_checksum(&self) -> bool {
+        self.metadata.contains_key(CHECKSUM_KEY) || self.metadata.contains_key(REPLY_CHECKSUM_KEY)
+    }
+
+    /// Sends the payload uncompressed if it is shorter than `threshold` bytes,
+    /// where compressing usually costs more than it saves. The compress bits
+    /// of the header are updated to match, so receivers decode it as usual.
+    pub fn apply_compress_threshold(&mut self, threshold: usize) {
+        if self.payload.len() < threshold {
+            self.header[2] &= !0x1C;
         }
+    }
 
-        let mut vp = Vec::with_capacity(payload.len());
-        match self.get_compress_type().unwrap() {
-            CompressType::Gzip => {
-                let mut deflater = GzDecoder::new(payload);
-                deflater.read_to_end(&mut vp)?;
-            }
-            CompressType::CompressNone => {
-                vp.extend_from_slice(&payload);
-            }
+    /// Appends the encoded message to `buf`, compressing the payload like
+    /// `encode` does.
+    pub fn encode_to(&self, buf: &mut BytesMut) {
+        let mut header = self.header;
+        let payload = wire_payload(&mut header, &self.metadata, &self.payload);
+        let metadata = wire_metadata(&self.metadata, &header, &payload);
+        buf.reserve(12 + 4 + self.body_len(&metadata, payload.len()));
+        self.write_frame(&header, &metadata, &payload, buf);
+    }
+
+    /// Encodes the message for vectored IO. Only the header, service path,
+    /// service method and metadata are copied, the payload is borrowed unless
+    /// it is compressed.
+    pub fn encode_vectored(&self) -> EncodedFrame<'_> {
+        let mut header = self.header;
+        let payload = wire_payload(&mut header, &self.metadata, &self.payload);
+        let metadata = wire_metadata(&self.metadata, &header, &payload);
+        let body_len = self.body_len(&metadata, payload.len());
+        let metadata = encode_metadata(&metadata);
+
+        let mut head = Vec::with_capacity(
+            12 + 4 + 4 + self.service_path.len() + 4 + self.service_method.len() + 4,
+        );
+        head.extend_from_slice(&header);
+        head.extend_from_slice(&write_len(body_len as u32));
+        put_chunk(&mut head, self.service_path.as_bytes());
+        put_chunk(&mut head, self.service_method.as_bytes());
+        head.extend_from_slice(&write_len(metadata.len() as u32));
+
+        EncodedFrame {
+            head,
+            metadata: Cow::Owned(metadata),
+            payload_len: write_len(payload.len() as u32),
+            payload,
+        }
+    }
+
+    /// Length of the frame without the header and the length prefix.
+    fn body_len(&self, metadata: &Metadata, payload_len: usize) -> usize {
+        16 + self.service_path.len()
+            + self.service_method.len()
+            + metadata_len(metadata)
+            + payload_len
+    }
+
+    fn write_frame<B: BufMut>(
+        &self,
+        header: &[u8; 12],
+        metadata: &Metadata,
+        payload: &[u8],
+        buf: &mut B,
+    ) {
+        buf.put_slice(header);
+        buf.put_u32(self.body_len(metadata, payload.len()) as u32);
+        put_chunk(buf, self.service_path.as_bytes());
+        put_chunk(buf, self.service_method.as_bytes());
+        buf.put_u32(metadata_len(metadata) as u32);
+        for (key, value) in metadata.iter() {
+            put_chunk(buf, key.as_bytes());
+            put_chunk(buf, value.as_bytes());
         }
-        self.payload = vp;
+        put_chunk(buf, payload);
+    }
 
-        Ok(())
+    /// Decodes a message like `decode`, rejecting frames which exceed `limits`.
+    pub fn decode_with_limits<R>(&mut self, r: &mut R, limits: &DecodeLimits) -> Result<()>
+    where
+        R: Read + ?Sized,
+    {
+        self.read_frame(r, limits)?
     }
 
-    fn encode(&self) -> Vec<u8> {
-        // encode all except header
-        let mut buf = Vec::<u8>::with_capacity(20);
-        buf.extend_from_slice(&self.header);
-
-        // push fake length
-        let len_bytes = write_len(0);
-        buf.extend_from_slice(&len_bytes);
-
-        // service_path
-        let len = self.service_path.len();
-        let len_bytes = write_len(len as u32);
-        buf.extend_from_slice(&len_bytes);
-        buf.extend_from_slice(self.service_path.as_bytes());
-
-        // service_method
-        let len = self.service_method.len();
-        let len_bytes = write_len(len as u32);
-        buf.extend_from_slice(&len_bytes);
-        buf.extend_from_slice(self.service_method.as_bytes());
-
-        // metadata
-        let mut metadata_bytes = Vec::<u8>::new();
-        let metadata = self.metadata.borrow_mut();
-        for meta in metadata.iter() {
-            let key = meta.0;
-            let len_bytes = write_len(key.len() as u32);
-            metadata_bytes.extend_from_slice(&len_bytes);
-            metadata_bytes.extend_from_slice(key.as_bytes());
-
-            let value = meta.1;
-            let len_bytes = write_len(value.len() as u32);
-            metadata_bytes.extend_from_slice(&len_bytes);
-            metadata_bytes.extend_from_slice(value.as_bytes());
+    /// Decodes a message like `decode_with_limits`, telling errors of the
+    /// frame from errors of its body.
+    ///
+    /// The outer error means the stream is broken. The inner one means the
+    /// frame was read whole but its body is invalid, e.g. because its payload
+    /// doesn't match its checksum: the header is set, so the call the frame
+    /// belongs to can be failed alone, and the next frame can be read.
+    pub fn read_frame<R>(&mut self, r: &mut R, limits: &DecodeLimits) -> Result<Result<()>>
+    where
+        R: Read + ?Sized,
+    {
+        r.read_exact(&mut self.header)?;
+        check_header(&self.header)?;
+
+        let mut buf = [0u8; 4];
+        r.read_exact(&mut buf[..])?;
+        let len = BigEndian::read_u32(&buf) as usize; //length of all expect header
+        if len > limits.max_frame_size {
+            return Err(protocol_error("frame exceeds the size limit"));
         }
-        let len = metadata_bytes.len();
-        let len_bytes = write_len(len as u32);
-        buf.extend_from_slice(&len_bytes);
-        buf.append(&mut metadata_bytes);
-
-        // data
-        // check compress
-
-        match self.get_compress_type().unwrap() {
-            CompressType::Gzip => {
-                let mut e = GzEncoder::new(Vec::new(), Compression::fast());
-                let _ = e.write_all(&self.payload[..]);
-                let compressed_payload = e.finish().unwrap();
-                let len = compressed_payload.len();
-                let len_bytes = write_len(len as u32);
-                buf.extend_from_slice(&len_bytes);
-                buf.extend_from_slice(&compressed_payload);
-            }
-            _ => {
-                let len = self.payload.len();
-                let len_bytes = write_len(len as u32);
-                buf.extend_from_slice(&len_bytes);
-                buf.extend_from_slice(&self.payload);
-            }
+
+        // don't trust the length for the allocation, grow while reading.
+        let mut buf = Vec::new();
+        r.take(len as u64).read_to_end(&mut buf)?;
+        if buf.len() != len {
+            return Err(Error::from(std::io::Error::from(
+                std::io::ErrorKind::UnexpectedEof,
+            )));
         }
 
-        // set the real length
-        let len = buf.len() - 12 - 4;
-        let len_bytes = write_len(len as u32);
-        buf[12] = len_bytes[0];
-        buf[13] = len_bytes[1];
-        buf[14] = len_bytes[2];
-        buf[15] = len_bytes[3];
+        Ok(self.decode_body(&buf, limits))
+    }
 
+    /// Decodes everything after the header and the length prefix, i.e. the
+    /// frame body, from a buffer that holds exactly one body.
+    pub(crate) fn decode_body(&mut self, buf: &[u8], limits: &DecodeLimits) -> Result<()> {
+        let ct = self
+            .get_compress_type()
+            .ok_or_else(|| protocol_error("unknown compress type"))?;
+        let body = BodyRanges::parse(buf, limits)?;
+        self.service_path = read_str(&buf[body.service_path])?;
+        self.service_method = read_str(&buf[body.service_method])?;
+        self.metadata = decode_metadata(&buf[body.metadata])?;
+        let key = checksum_key(&self.header);
+        verify_checksum(
+            self.metadata.get(key).map(String::as_str),
+            &buf[body.payload.clone()],
+        )?;
+        // encrypted payloads are decompressed when they are opened
+        self.payload = if self.metadata.contains_key(ENCRYPTION_KEY_ID) {
+            buf[body.payload].to_vec()
+        } else {
+            decompress(ct, &buf[body.payload], limits.max_payload_size)?
+        };
+
+        Ok(())
+    }
+}
+
+impl RpcxMessage for Message {
+    fn header(&self) -> &[u8; 12] {
+        &self.header
+    }
+    fn header_mut(&mut self) -> &mut [u8; 12] {
+        &mut self.header
+    }
+
+    fn decode<R: ?Sized>(&mut self, r: &mut R) -> Result<()>
+    where
+        R: Read,
+    {
+        self.decode_with_limits(r, &DecodeLimits::default())
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut header = self.header;
+        let payload = wire_payload(&mut header, &self.metadata, &self.payload);
+        let metadata = wire_metadata(&self.metadata, &header, &payload);
+        let mut buf = Vec::with_capacity(12 + 4 + self.body_len(&metadata, payload.len()));
+        self.write_frame(&header, &metadata, &payload, &mut buf);
         buf
     }
 
     fn get_error(&self) -> Option<String> {
         match self.get_message_status_type() {
             Some(MessageStatusType::Error) => {
-                let metadata = &self.metadata;
-                let metadata2 = metadata.borrow();
-                let err_msg = metadata2.get(&SERVICE_ERROR.to_owned())?;
+                let err_msg = self.metadata.error()?;
                 Some(String::from(err_msg))
             }
             _ => None,
@@ -319,19 +528,372 @@ impl RpcxMessage for Message {
     }
 }
 
-fn read_len(buf: &[u8]) -> u32 {
+/// builds a `Message` in one chain instead of a sequence of `set_*` calls.
+///
+/// `build` checks that the compress and serialize types fit in the header
+/// and that requests other than heartbeats name a service.
+#[derive(Debug)]
+pub struct MessageBuilder {
+    msg: Message,
+    compress_type: CompressType,
+    serialize_type: SerializeType,
+}
+
+impl Default for MessageBuilder {
+    fn default() -> Self {
+        MessageBuilder {
+            msg: Message::new(),
+            compress_type: CompressType::CompressNone,
+            serialize_type: SerializeType::SerializeNone,
+        }
+    }
+}
+
+impl MessageBuilder {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn version(mut self, v: u8) -> Self {
+        self.msg.set_version(v);
+        self
+    }
+
+    pub fn message_type(mut self, mt: MessageType) -> Self {
+        self.msg.set_message_type(mt);
+        self
+    }
+
+    pub fn message_status_type(mut self, mst: MessageStatusType) -> Self {
+        self.msg.set_message_status_type(mst);
+        self
+    }
+
+    pub fn heartbeat(mut self, b: bool) -> Self {
+        self.msg.set_heartbeat(b);
+        self
+    }
+
+    pub fn oneway(mut self, b: bool) -> Self {
+        self.msg.set_oneway(b);
+        self
+    }
+
+    pub fn compress_type(mut self, ct: CompressType) -> Self {
+        self.compress_type = ct;
+        self
+    }
+
+    pub fn serialize_type(mut self, st: SerializeType) -> Self {
+        self.serialize_type = st;
+        self
+    }
+
+    pub fn seq(mut self, seq: u64) -> Self {
+        self.msg.set_seq(seq);
+        self
+    }
+
+    pub fn service_path<S: Into<String>>(mut self, service_path: S) -> Self {
+        self.msg.service_path = service_path.into();
+        self
+    }
+
+    pub fn service_method<S: Into<String>>(mut self, service_method: S) -> Self {
+        self.msg.service_method = service_method.into();
+        self
+    }
+
+    /// Replaces all metadata.
+    pub fn metadata(mut self, metadata: Metadata) -> Self {
+        self.msg.metadata = metadata;
+        self
+    }
+
+    /// Adds one metadata entry.
+    pub fn meta<K: Into<String>, V: Into<String>>(mut self, key: K, value: V) -> Self {
+        self.msg.metadata.insert(key.into(), value.into());
+        self
+    }
+
+    pub fn payload(mut self, payload: Vec<u8>) -> Self {
+        self.msg.payload = payload;
+        self
+    }
+
+    /// Sends a checksum of the payload, see `Message::set_checksum`.
+    pub fn checksum(mut self, enabled: bool) -> Self {
+        self.msg.set_checksum(enabled);
+        self
+    }
+
+    pub fn build(self) -> Result<Message> {
+        let mut msg = self.msg;
+        msg.set_compress_type(self.compress_type)?;
+        msg.set_serialize_type(self.serialize_type)?;
+
+        if msg.get_message_type() == Some(MessageType::Request)
+            && !msg.is_heartbeat()
+            && (msg.service_path.is_empty() || msg.service_method.is_empty())
+        {
+            return Err(Error::new(
+                ErrorKind::Other,
+                "service path and method are required",
+            ));
+        }
+        Ok(msg)
+    }
+}
+
+/// positions of the parts of a frame body, relative to the start of the body.
+#[derive(Debug)]
+pub(crate) struct BodyRanges {
+    pub service_path: Range<usize>,
+    pub service_method: Range<usize>,
+    pub metadata: Range<usize>,
+    pub payload: Range<usize>,
+}
+
+impl BodyRanges {
+    pub(crate) fn parse(buf: &[u8], limits: &DecodeLimits) -> Result<Self> {
+        if buf.len() > limits.max_frame_size {
+            return Err(protocol_error("frame exceeds the size limit"));
+        }
+
+        let service_path = read_chunk(buf, 0)?;
+        let service_method = read_chunk(buf, service_path.end)?;
+        let metadata = read_chunk(buf, service_method.end)?;
+        if metadata.len() > limits.max_metadata_size {
+            return Err(protocol_error("metadata exceeds the size limit"));
+        }
+        let payload = read_chunk(buf, metadata.end)?;
+        if payload.end != buf.len() {
+            return Err(protocol_error("invalid payload length"));
+        }
+        if payload.len() > limits.max_payload_size {
+            return Err(protocol_error("payload exceeds the size limit"));
+        }
+
+        Ok(BodyRanges {
+            service_path,
+            service_method,
+            metadata,
+            payload,
+        })
+    }
+}
+
+/// iterates over the raw key/value pairs of encoded metadata without copying.
+pub(crate) struct MetadataIter<'a> {
+    buf: &'a [u8],
+    start: usize,
+}
+
+impl<'a> MetadataIter<'a> {
+    pub(crate) fn new(buf: &'a [u8]) -> Self {
+        MetadataIter { buf, start: 0 }
+    }
+
+    fn next_pair(&mut self) -> Result<(&'a str, &'a str)> {
+        let buf = self.buf;
+        let key = read_chunk(buf, self.start)?;
+        if key.end >= buf.len() {
+            // a trailing key without value
+            self.start = buf.len();
+            return Ok((as_str(&buf[key])?, ""));
+        }
+
+        let value = read_chunk(buf, key.end)?;
+        self.start = value.end;
+        Ok((as_str(&buf[key])?, as_str(&buf[value])?))
+    }
+}
+
+impl<'a> Iterator for MetadataIter<'a> {
+    type Item = Result<(&'a str, &'a str)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.start >= self.buf.len() {
+            return None;
+        }
+
+        let pair = self.next_pair();
+        if pair.is_err() {
+            // stop after the first malformed pair
+            self.start = self.buf.len();
+        }
+        Some(pair)
+    }
+}
+
+pub(crate) fn decode_metadata(buf: &[u8]) -> Result<Metadata> {
+    let mut metadata = HashMap::new();
+    for kv in MetadataIter::new(buf) {
+        let (key, value) = kv?;
+        metadata.insert(key.to_owned(), value.to_owned());
+    }
+    Ok(metadata)
+}
+
+pub(crate) fn encode_metadata(metadata: &Metadata) -> Vec<u8> {
+    let mut metadata_bytes = Vec::<u8>::with_capacity(metadata_len(metadata));
+    for (key, value) in metadata.iter() {
+        put_chunk(&mut metadata_bytes, key.as_bytes());
+        put_chunk(&mut metadata_bytes, value.as_bytes());
+    }
+    metadata_bytes
+}
+
+/// Returns the length of the encoded metadata.
+fn metadata_len(metadata: &Metadata) -> usize {
+    metadata.iter().map(|(k, v)| 8 + k.len() + v.len()).sum()
+}
+
+/// Returns the metadata key for the checksum of a message with `header`.
+///
+/// Go rpcx servers copy the metadata of a request into the reply, so
+/// requests and replies use different keys and the stale checksum of a
+/// request is never verified against the payload of its reply.
+pub(crate) fn checksum_key(header: &[u8; 12]) -> &'static str {
+    if header[2] & 0x80 == 0 {
+        CHECKSUM_KEY
+    } else {
+        REPLY_CHECKSUM_KEY
+    }
+}
+
+/// Returns the metadata to send with `payload`, the payload as it is on the
+/// wire. If a checksum was asked for, the key for the message type holds the
+/// CRC32C of the payload and the other key is dropped.
+pub(crate) fn wire_metadata<'a>(
+    metadata: &'a Metadata,
+    header: &[u8; 12],
+    payload: &[u8],
+) -> Cow<'a, Metadata> {
+    if !metadata.contains_key(CHECKSUM_KEY) && !metadata.contains_key(REPLY_CHECKSUM_KEY) {
+        return Cow::Borrowed(metadata);
+    }
+
+    let mut metadata = metadata.clone();
+    metadata.remove(CHECKSUM_KEY);
+    metadata.remove(REPLY_CHECKSUM_KEY);
+    metadata.insert(
+        checksum_key(header).to_owned(),
+        format!("{:08x}", crc32c::crc32c(payload)),
+    );
+    Cow::Owned(metadata)
+}
+
+/// Checks `payload`, as it is on the wire, against the checksum sent with
+/// it, if any.
+pub(crate) fn verify_checksum(checksum: Option<&str>, payload: &[u8]) -> Result<()> {
+    match checksum {
+        None => Ok(()),
+        Some(checksum) => match u32::from_str_radix(checksum, 16) {
+            Ok(crc) if checksum.len() == 8 && crc == crc32c::crc32c(payload) => Ok(()),
+            _ => Err(protocol_error("payload checksum mismatch")),
+        },
+    }
+}
+
+/// Writes `data` with its length prefix.
+pub(crate) fn put_chunk<B: BufMut>(buf: &mut B, data: &[u8]) {
+    buf.put_u32(data.len() as u32);
+    buf.put_slice(data);
+}
+
+/// Returns the payload as it is sent: compressed, unless it is encrypted
+/// and so was compressed before it was sealed.
+pub(crate) fn wire_payload<'a>(
+    header: &mut [u8; 12],
+    metadata: &Metadata,
+    payload: &'a [u8],
+) -> Cow<'a, [u8]> {
+    if metadata.contains_key(ENCRYPTION_KEY_ID) {
+        Cow::Borrowed(payload)
+    } else {
+        compress(header, payload)
+    }
+}
+
+/// Compresses `payload` with the compressor for the compress type in
+/// `header`. If there is no such compressor or it fails, the payload is left
+/// uncompressed and the compress bits of `header` are cleared to match.
+pub(crate) fn compress<'a>(header: &mut [u8; 12], payload: &'a [u8]) -> Cow<'a, [u8]> {
+    let ct = CompressType::from_u8((header[2] & 0x1C) >> 2);
+    if let Some(CompressType::CompressNone) = ct {
+        return Cow::Borrowed(payload);
+    }
+
+    match ct.and_then(get_compressor).map(|c| c.compress(payload)) {
+        Some(Ok(compressed)) => Cow::Owned(compressed),
+        _ => {
+            header[2] &= !0x1C;
+            Cow::Borrowed(payload)
+        }
+    }
+}
+
+/// Decompresses `payload`, failing when the result would exceed `max_len`.
+pub(crate) fn decompress(ct: CompressType, payload: &[u8], max_len: usize) -> Result<Vec<u8>> {
+    let vp = match ct {
+        CompressType::CompressNone => payload.to_vec(),
+        _ => get_compressor(ct)
+            .ok_or_else(|| protocol_error("unsupported compress type"))?
+            .decompress(payload, max_len)?,
+    };
+    if vp.len() > max_len {
+        return Err(protocol_error("payload exceeds the size limit"));
+    }
+    Ok(vp)
+}
+
+pub(crate) fn read_len(buf: &[u8]) -> u32 {
     BigEndian::read_u32(&buf[..4])
 }
 
-fn write_len(len: u32) -> [u8; 4] {
+pub(crate) fn write_len(len: u32) -> [u8; 4] {
     let mut buf = [0u8; 4];
     BigEndian::write_u32(&mut buf, len);
     buf
 }
 
+/// Returns the range of the length-prefixed chunk which starts at `start`.
+fn read_chunk(buf: &[u8], start: usize) -> Result<Range<usize>> {
+    let data_start = start
+        .checked_add(4)
+        .filter(|&end| end <= buf.len())
+        .ok_or_else(|| protocol_error("frame is truncated"))?;
+    let len = read_len(&buf[start..data_start]) as usize;
+    let data_end = data_start
+        .checked_add(len)
+        .filter(|&end| end <= buf.len())
+        .ok_or_else(|| protocol_error("frame is truncated"))?;
+    Ok(data_start..data_end)
+}
+
+pub(crate) fn protocol_error(msg: &'static str) -> Error {
+    Error::new(ErrorKind::Protocol, msg)
+}
+
+/// Checks the magic number and the version of a header, or of as much of it
+/// as has been read.
+pub(crate) fn check_header(header: &[u8]) -> Result<()> {
+    if !header.is_empty() && header[0] != MAGIC_NUMBER {
+        return Err(protocol_error("invalid magic number"));
+    }
+    if header.len() > 1 && header[1] > PROTOCOL_VERSION {
+        return Err(protocol_error("unsupported protocol version"));
+    }
+    Ok(())
+}
+
+fn as_str(buf: &[u8]) -> Result<&str> {
+    std::str::from_utf8(buf).map_err(|err| Error::new(ErrorKind::Protocol, err))
+}
+
 fn read_str(buf: &[u8]) -> Result<String> {
-    let s = std::str::from_utf8(&buf).unwrap();
-    let str: String = std::string::String::from(s);
+    let str: String = std::string::String::from(as_str(buf)?);
     Ok(str)
 }
 
@@ -391,8 +953,8 @@ mod tests {
         msg.set_message_type(MessageType::Response);
         msg.set_heartbeat(true);
         msg.set_oneway(true);
-        msg.set_compress_type(CompressType::Gzip);
-        msg.set_serialize_type(SerializeType::MsgPack);
+        msg.set_compress_type(CompressType::Gzip).unwrap();
+        msg.set_serialize_type(SerializeType::MsgPack).unwrap();
         msg.set_message_status_type(MessageStatusType::Normal);
         msg.set_seq(1000000);
 
@@ -433,7 +995,7 @@ mod tests {
 
         assert_eq!(
             "6ba7b810-9dad-11d1-80b4-00c04fd430c9",
-            msg.metadata.borrow().get("__ID").unwrap()
+            msg.metadata.get("__ID").unwrap()
         );
 
         assert_eq!(
@@ -464,4 +1026,169 @@ mod tests {
 
         assert_eq!(&msg_data[..], &encoded_bytes[..]);
     }
+
+    #[test]
+    fn compress_threshold() {
+        let mut msg = Message::new();
+        msg.set_compress_type(CompressType::Gzip).unwrap();
+        msg.payload = b"{\"A\":1,\"B\":2}".to_vec();
+
+        msg.apply_compress_threshold(msg.payload.len());
+        assert_eq!(CompressType::Gzip, msg.get_compress_type().unwrap());
+
+        msg.apply_compress_threshold(1024);
+        assert_eq!(CompressType::CompressNone, msg.get_compress_type().unwrap());
+        let data = msg.encode();
+        assert_eq!(&msg.payload[..], &data[data.len() - msg.payload.len()..]);
+
+        let mut decoded = Message::new();
+        decoded.decode(&mut &data[..]).unwrap();
+        assert_eq!(msg.payload, decoded.payload);
+    }
+
+    #[test]
+    fn builder() {
+        let msg = MessageBuilder::new()
+            .oneway(true)
+            .compress_type(CompressType::Gzip)
+            .serialize_type(SerializeType::MsgPack)
+            .seq(42)
+            .service_path("Arith")
+            .service_method("Mul")
+            .meta("key", "value")
+            .payload(b"data".to_vec())
+            .build()
+            .unwrap();
+        assert_eq!(MessageType::Request, msg.get_message_type().unwrap());
+        assert!(msg.is_oneway());
+        assert!(!msg.is_heartbeat());
+        assert_eq!(CompressType::Gzip, msg.get_compress_type().unwrap());
+        assert_eq!(SerializeType::MsgPack, msg.get_serialize_type().unwrap());
+        assert_eq!(42, msg.get_seq());
+        assert_eq!("value", msg.metadata["key"]);
+
+        let reply = msg.get_reply().unwrap();
+        assert_eq!(MessageType::Response, reply.get_message_type().unwrap());
+        assert!(!reply.is_oneway());
+        assert_eq!(msg.header[2] & 0x1C, reply.header[2] & 0x1C);
+        assert_eq!(msg.header[3], reply.header[3]);
+        assert_eq!(42, reply.get_seq());
+        assert_eq!("Mul", reply.service_method);
+
+        assert!(MessageBuilder::new().service_path("Arith").build().is_err());
+        assert!(MessageBuilder::new().heartbeat(true).build().is_ok());
+        assert!(MessageBuilder::new()
+            .heartbeat(true)
+            .compress_type(CompressType::Custom(8))
+            .build()
+            .is_err());
+    }
+
+    #[test]
+    fn reject_out_of_range_custom_types() {
+        let mut msg = Message::new();
+        msg.set_compress_type(CompressType::Custom(7)).unwrap();
+        msg.set_serialize_type(SerializeType::Custom(15)).unwrap();
+        let header = msg.header;
+
+        assert!(msg.set_compress_type(CompressType::Custom(8)).is_err());
+        assert!(msg.set_compress_type(CompressType::Custom(2)).is_err());
+        assert!(msg.set_serialize_type(SerializeType::Custom(16)).is_err());
+        assert_eq!(header, msg.header);
+        assert_eq!(Some(CompressType::Custom(7)), msg.get_compress_type());
+        assert_eq!(Some(SerializeType::Custom(15)), msg.get_serialize_type());
+    }
+
+    #[test]
+    fn reject_unknown_version() {
+        let msg = MessageBuilder::new().heartbeat(true).build().unwrap();
+        assert_eq!(PROTOCOL_VERSION, msg.get_version());
+        let mut data = msg.encode();
+        data[1] = PROTOCOL_VERSION + 1;
+
+        let err = Message::new().decode(&mut &data[..]).unwrap_err();
+        assert_eq!(ErrorKind::Protocol, err.kind());
+    }
+
+    #[test]
+    fn checksum() {
+        let msg = MessageBuilder::new()
+            .compress_type(CompressType::Gzip)
+            .seq(7)
+            .service_path("Arith")
+            .service_method("Mul")
+            .payload(vec![7u8; 1000])
+            .checksum(true)
+            .build()
+            .unwrap();
+        let data = msg.encode();
+
+        let mut decoded = Message::new();
+        decoded.decode(&mut &data[..]).unwrap();
+        assert_eq!(msg.payload, decoded.payload);
+        assert_eq!(8, decoded.metadata[CHECKSUM_KEY].len());
+        assert!(!decoded.metadata.contains_key(REPLY_CHECKSUM_KEY));
+        let mut header = [0u8; 12];
+        header.copy_from_slice(&data[..12]);
+        let frame = crate::Frame::from_parts(header, data[16..].to_vec().into()).unwrap();
+        assert_eq!(crate::Frame::from(&msg).encode(), frame.encode());
+
+        // a flipped bit in the compressed payload is caught before inflating
+        let mut corrupted = data.clone();
+        *corrupted.last_mut().unwrap() ^= 0x01;
+        let err = Message::new().decode(&mut &corrupted[..]).unwrap_err();
+        assert_eq!(ErrorKind::Protocol, err.kind());
+        let err = crate::Frame::new().decode(&mut &corrupted[..]).unwrap_err();
+        assert_eq!(ErrorKind::Protocol, err.kind());
+
+        // replies carry a checksum under their own key
+        let mut reply = decoded.get_reply().unwrap();
+        reply.payload = b"reply".to_vec();
+        let mut decoded = Message::new();
+        decoded.decode(&mut &reply.encode()[..]).unwrap();
+        assert!(decoded.metadata.contains_key(REPLY_CHECKSUM_KEY));
+        assert!(!decoded.metadata.contains_key(CHECKSUM_KEY));
+
+        // Go servers echo the request checksum in the reply, which is ignored
+        let mut echo = crate::Frame::from(&reply);
+        let mut metadata = Metadata::new();
+        metadata.insert(CHECKSUM_KEY.to_owned(), "00000000".to_owned());
+        echo.set_metadata(&metadata);
+        let mut decoded = Message::new();
+        decoded.decode(&mut &echo.encode()[..]).unwrap();
+        assert_eq!(b"reply".to_vec(), decoded.payload);
+
+        // and without the option nothing is added
+        let mut plain = msg;
+        plain.set_checksum(false);
+        assert!(!plain.has_checksum());
+        let mut decoded = Message::new();
+        decoded.decode(&mut &plain.encode()[..]).unwrap();
+        assert!(decoded.metadata.is_empty());
+    }
+
+    #[test]
+    fn reply_error() {
+        let req = Message::builder()
+            .service_path("Arith")
+            .service_method("Div")
+            .build()
+            .unwrap();
+        let mut reply = req.get_reply().unwrap();
+        assert!(reply.reply_error().is_none());
+
+        reply.set_reply_error(&Error::new(ErrorKind::NotFound, "method Div not found"));
+        let mut decoded = Message::new();
+        decoded.decode(&mut &reply.encode()[..]).unwrap();
+        let err = decoded.reply_error().unwrap();
+        assert_eq!(ErrorKind::NotFound, err.kind());
+        assert_eq!("method Div not found", err.to_string());
+
+        // errors of Go servers have no kind
+        decoded.metadata.remove(crate::ERROR_KIND_KEY);
+        decoded.metadata.set_error("rpcx: can't find service Arith");
+        assert_eq!(ErrorKind::NotFound, decoded.reply_error().unwrap().kind());
+        decoded.metadata.set_error("division by zero");
+        assert_eq!(ErrorKind::Other, decoded.reply_error().unwrap().kind());
+    }
 }
@@ -0,0 +1,257 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use crate::{ErrorKind, Metadata};
+
+/// the error message of a reply whose status is `MessageStatusType::Error`.
+pub const SERVICE_ERROR: &str = "__rpcx_error__";
+/// the `ErrorKind` of an error reply by name, Go rpcx doesn't send it.
+pub const ERROR_KIND_KEY: &str = "__rpcx_error_kind";
+/// the code and the JSON details of a `ServerError`.
+pub const ERROR_CODE_KEY: &str = "__rpcx_error_code";
+pub const ERROR_DETAILS_KEY: &str = "__rpcx_error_details";
+/// the auth token, checked by the server's auth plugin in Go rpcx.
+pub const AUTH_KEY: &str = "__AUTH";
+/// an id for tracking a request across services.
+pub const REQUEST_ID: &str = "__ID";
+/// the time the server has left to handle a request, in milliseconds.
+pub const SERVER_TIMEOUT: &str = "__ServerTimeout";
+/// the W3C trace context headers.
+pub const TRACEPARENT: &str = "traceparent";
+pub const TRACESTATE: &str = "tracestate";
+/// the protocol version and features a client offers in its first
+/// heartbeat, see `Handshake`.
+pub const VERSION_KEY: &str = "__rpcx_version";
+pub const FEATURES_KEY: &str = "__rpcx_features";
+/// the protocol version and features the server agreed on.
+pub const ACCEPT_VERSION_KEY: &str = "__rpcx_accept_version";
+pub const ACCEPT_FEATURES_KEY: &str = "__rpcx_accept_features";
+/// the CRC32C of the payload as sent, eight hex digits, in requests and
+/// replies. See `Message::set_checksum`.
+pub const CHECKSUM_KEY: &str = "__rpcx_crc32c";
+pub const REPLY_CHECKSUM_KEY: &str = "__rpcx_reply_crc32c";
+/// the id of the key an encrypted payload was sealed with, see `Encryption`.
+pub const ENCRYPTION_KEY_ID: &str = "__rpcx_key_id";
+/// the position of a fragment as `index/count`, see `split`.
+pub const FRAGMENT_KEY: &str = "__rpcx_fragment";
+/// the seq of the call a oneway cancel notification cancels, see
+/// `CANCEL_FEATURE`.
+pub const CANCEL_KEY: &str = "__rpcx_cancel";
+
+/// a W3C trace context, see https://www.w3.org/TR/trace-context/.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct TraceContext {
+    pub traceparent: String,
+    pub tracestate: Option<String>,
+}
+
+/// typed accessors for the well-known keys of `Metadata`.
+///
+/// The keys and value encodings match Go rpcx, so Rust and Go peers read
+/// each other's metadata.
+pub trait MetadataExt {
+    fn error(&self) -> Option<&str>;
+    fn set_error(&mut self, err: &str);
+    /// Returns the kind of the error, `None` if it is missing or unknown.
+    fn error_kind(&self) -> Option<ErrorKind>;
+    fn set_error_kind(&mut self, kind: ErrorKind);
+
+    fn auth(&self) -> Option<&str>;
+    fn set_auth(&mut self, token: &str);
+
+    fn request_id(&self) -> Option<&str>;
+    fn set_request_id(&mut self, id: &str);
+
+    /// Returns the server timeout, `None` if it is missing or malformed.
+    fn timeout(&self) -> Option<Duration>;
+    fn set_timeout(&mut self, timeout: Duration);
+    /// Sets the server timeout to the time left until `deadline`, which is
+    /// how Go rpcx sends a context deadline.
+    fn set_deadline(&mut self, deadline: Instant) {
+        self.set_timeout(deadline.saturating_duration_since(Instant::now()));
+    }
+
+    fn trace_context(&self) -> Option<TraceContext>;
+    fn set_trace_context(&mut self, ctx: &TraceContext);
+}
+
+impl MetadataExt for Metadata {
+    fn error(&self) -> Option<&str> {
+        self.get(SERVICE_ERROR).map(String::as_str)
+    }
+    fn set_error(&mut self, err: &str) {
+        self.insert(SERVICE_ERROR.to_owned(), err.to_owned());
+    }
+    fn error_kind(&self) -> Option<ErrorKind> {
+        ErrorKind::from_name(self.get(ERROR_KIND_KEY)?)
+    }
+    fn set_error_kind(&mut self, kind: ErrorKind) {
+        self.insert(ERROR_KIND_KEY.to_owned(), kind.name().to_owned());
+    }
+
+    fn auth(&self) -> Option<&str> {
+        self.get(AUTH_KEY).map(String::as_str)
+    }
+    fn set_auth(&mut self, token: &str) {
+        self.insert(AUTH_KEY.to_owned(), token.to_owned());
+    }
+
+    fn request_id(&self) -> Option<&str> {
+        self.get(REQUEST_ID).map(String::as_str)
+    }
+    fn set_request_id(&mut self, id: &str) {
+        self.insert(REQUEST_ID.to_owned(), id.to_owned());
+    }
+
+    fn timeout(&self) -> Option<Duration> {
+        let ms = self.get(SERVER_TIMEOUT)?.parse::<u64>().ok()?;
+        Some(Duration::from_millis(ms))
+    }
+    fn set_timeout(&mut self, timeout: Duration) {
+        self.insert(SERVER_TIMEOUT.to_owned(), timeout.as_millis().to_string());
+    }
+
+    fn trace_context(&self) -> Option<TraceContext> {
+        Some(TraceContext {
+            traceparent: self.get(TRACEPARENT)?.clone(),
+            tracestate: self.get(TRACESTATE).cloned(),
+        })
+    }
+    fn set_trace_context(&mut self, ctx: &TraceContext) {
+        self.insert(TRACEPARENT.to_owned(), ctx.traceparent.clone());
+        match ctx.tracestate {
+            Some(ref state) => self.insert(TRACESTATE.to_owned(), state.clone()),
+            None => self.remove(TRACESTATE),
+        };
+    }
+}
+
+/// the metadata a server registers its services with, a query string like
+/// `weight=10&group=test`.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ServiceMeta {
+    values: HashMap<String, String>,
+}
+
+impl ServiceMeta {
+    pub fn parse(s: &str) -> Self {
+        let values = s
+            .split('&')
+            .filter(|kv| !kv.is_empty())
+            .map(|kv| {
+                let mut parts = kv.splitn(2, '=');
+                let k = unescape(parts.next().unwrap_or_default());
+                let v = unescape(parts.next().unwrap_or_default());
+                (k, v)
+            })
+            .collect();
+        ServiceMeta { values }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.values.get(key).map(String::as_str)
+    }
+
+    /// Returns the weight for weighted selection, `None` if it is missing or
+    /// malformed.
+    pub fn weight(&self) -> Option<isize> {
+        self.get("weight")?.parse().ok()
+    }
+
+    pub fn group(&self) -> Option<&str> {
+        self.get("group")
+    }
+
+    /// Returns false if the server marked the service `state=inactive`.
+    pub fn is_active(&self) -> bool {
+        self.get("state") != Some("inactive")
+    }
+}
+
+/// Decodes `+` and `%XX` escapes, leaving malformed escapes as they are.
+fn unescape(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut buf = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => buf.push(b' '),
+            b'%' if i + 2 < bytes.len() => match (hex(bytes[i + 1]), hex(bytes[i + 2])) {
+                (Some(hi), Some(lo)) => {
+                    buf.push(hi << 4 | lo);
+                    i += 2;
+                }
+                _ => buf.push(b'%'),
+            },
+            b => buf.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&buf).into_owned()
+}
+
+fn hex(b: u8) -> Option<u8> {
+    (b as char).to_digit(16).map(|d| d as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accessors() {
+        let mut metadata = Metadata::new();
+        assert_eq!(None, metadata.error());
+        assert_eq!(None, metadata.timeout());
+
+        metadata.set_error("service Arith.Mul not found");
+        metadata.set_auth("bearer tGzv3JOkF0XG5Qx2TlKWIA");
+        metadata.set_request_id("6ba7b810-9dad-11d1-80b4-00c04fd430c9");
+        metadata.set_timeout(Duration::from_millis(1500));
+        assert_eq!("1500", metadata[SERVER_TIMEOUT]);
+        assert_eq!(Some("service Arith.Mul not found"), metadata.error());
+        assert_eq!(Some("bearer tGzv3JOkF0XG5Qx2TlKWIA"), metadata.auth());
+        assert_eq!(
+            Some("6ba7b810-9dad-11d1-80b4-00c04fd430c9"),
+            metadata.request_id()
+        );
+        assert_eq!(Some(Duration::from_millis(1500)), metadata.timeout());
+
+        assert_eq!(None, metadata.error_kind());
+        metadata.set_error_kind(ErrorKind::NotFound);
+        assert_eq!("not_found", metadata[ERROR_KIND_KEY]);
+        assert_eq!(Some(ErrorKind::NotFound), metadata.error_kind());
+
+        let ctx = TraceContext {
+            traceparent: "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01".to_owned(),
+            tracestate: Some("congo=t61rcWkgMzE".to_owned()),
+        };
+        metadata.set_trace_context(&ctx);
+        assert_eq!(Some(ctx.clone()), metadata.trace_context());
+        metadata.set_trace_context(&TraceContext {
+            tracestate: None,
+            ..ctx
+        });
+        assert!(!metadata.contains_key(TRACESTATE));
+
+        metadata.insert(SERVER_TIMEOUT.to_owned(), "soon".to_owned());
+        assert_eq!(None, metadata.timeout());
+    }
+
+    #[test]
+    fn service_meta() {
+        let meta = ServiceMeta::parse("weight=10&group=test%20a&state=inactive&flag");
+        assert_eq!(Some(10), meta.weight());
+        assert_eq!(Some("test a"), meta.group());
+        assert_eq!(Some(""), meta.get("flag"));
+        assert!(!meta.is_active());
+
+        let meta = ServiceMeta::parse("");
+        assert_eq!(None, meta.weight());
+        assert!(meta.is_active());
+        assert_eq!("100%", unescape("100%"));
+        assert_eq!("%zz", unescape("%zz"));
+    }
+}
@@ -0,0 +1,184 @@
+use lazy_static::lazy_static;
+use num_traits::ToPrimitive;
+use serde::{de::DeserializeOwned, Serialize};
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
+
+use crate::{Error, ErrorKind, Result, SerializeType};
+
+/// the callback a `Serializer` hands its deserializer to. It reads one value
+/// of the type the caller asked for.
+pub type DeserializeFn<'a> = &'a mut dyn FnMut(&mut dyn erased_serde::Deserializer) -> Result<()>;
+
+/// serializes and deserializes payloads for one `SerializeType`.
+///
+/// Values are passed through `erased_serde`, so a serializer works for any
+/// type implementing serde's `Serialize` and `Deserialize`. Implementations
+/// are registered with `register_serializer` and shared by all messages, so
+/// they must be thread-safe.
+pub trait Serializer: Send + Sync {
+    fn serialize(&self, value: &dyn erased_serde::Serialize) -> Result<Vec<u8>>;
+    /// Creates a deserializer for `data` and passes it to `visit`, e.g.
+    /// `visit(&mut <dyn erased_serde::Deserializer>::erase(&mut de))`.
+    fn deserialize(&self, data: &[u8], visit: DeserializeFn) -> Result<()>;
+}
+
+lazy_static! {
+    static ref SERIALIZERS: RwLock<HashMap<u8, Arc<dyn Serializer>>> = {
+        let mut m: HashMap<u8, Arc<dyn Serializer>> = HashMap::new();
+        m.insert(1, Arc::new(JsonSerializer));
+        m.insert(3, Arc::new(MsgPackSerializer));
+        RwLock::new(m)
+    };
+}
+
+/// Registers `s` for `st`, replacing the serializer registered before.
+///
+/// Use it to plug in a serializer for a `SerializeType::Custom` id, e.g. CBOR
+/// or bincode, which all types deriving `RpcxParam` then support.
+pub fn register_serializer(st: SerializeType, s: Arc<dyn Serializer>) -> Result<()> {
+    let id = serialize_id(st)?;
+    if id == 0 {
+        return Err(Error::new(
+            ErrorKind::Other,
+            "SerializeNone can't have a serializer",
+        ));
+    }
+    SERIALIZERS.write().unwrap().insert(id, s);
+    Ok(())
+}
+
+/// Returns the serializer registered for `st`.
+pub fn get_serializer(st: SerializeType) -> Option<Arc<dyn Serializer>> {
+    let id = serialize_id(st).ok()?;
+    SERIALIZERS.read().unwrap().get(&id).cloned()
+}
+
+/// Serializes `value` with the serializer registered for `st`.
+pub fn serialize_with<T>(st: SerializeType, value: &T) -> Result<Vec<u8>>
+where
+    T: Serialize,
+{
+    lookup(st)?.serialize(value)
+}
+
+/// Deserializes a `T` from `data` with the serializer registered for `st`.
+pub fn deserialize_with<T>(st: SerializeType, data: &[u8]) -> Result<T>
+where
+    T: DeserializeOwned,
+{
+    let mut value = None;
+    lookup(st)?.deserialize(data, &mut |de| {
+        value = Some(
+            erased_serde::deserialize(de)
+                .map_err(|err| Error::new(ErrorKind::Serialization, err))?,
+        );
+        Ok(())
+    })?;
+    value.ok_or_else(|| Error::new(ErrorKind::Other, "serializer produced no value"))
+}
+
+fn lookup(st: SerializeType) -> Result<Arc<dyn Serializer>> {
+    get_serializer(st).ok_or_else(|| Error::new(ErrorKind::Other, "unknown format"))
+}
+
+fn serialize_id(st: SerializeType) -> Result<u8> {
+    st.to_u8()
+        .ok_or_else(|| Error::new(ErrorKind::Other, "serialize ids must fit in 4 bits"))
+}
+
+/// JSON, via serde_json.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct JsonSerializer;
+
+impl Serializer for JsonSerializer {
+    fn serialize(&self, value: &dyn erased_serde::Serialize) -> Result<Vec<u8>> {
+        Ok(serde_json::to_vec(value)?)
+    }
+    fn deserialize(&self, data: &[u8], visit: DeserializeFn) -> Result<()> {
+        let mut de = serde_json::Deserializer::from_slice(data);
+        visit(&mut <dyn erased_serde::Deserializer>::erase(&mut de))?;
+        Ok(de.end()?)
+    }
+}
+
+/// MessagePack, via rmp-serde. Structs are encoded as arrays like rmp-serde
+/// does by default.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct MsgPackSerializer;
+
+impl Serializer for MsgPackSerializer {
+    fn serialize(&self, value: &dyn erased_serde::Serialize) -> Result<Vec<u8>> {
+        rmp_serde::to_vec(value).map_err(|err| Error::new(ErrorKind::Serialization, err))
+    }
+    fn deserialize(&self, data: &[u8], visit: DeserializeFn) -> Result<()> {
+        let mut de = rmp_serde::Deserializer::new(data);
+        visit(&mut <dyn erased_serde::Deserializer>::erase(&mut de))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+
+    #[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+    struct Args {
+        #[serde(rename = "A")]
+        a: u64,
+        #[serde(rename = "B")]
+        b: u64,
+    }
+
+    #[test]
+    fn built_in_serializers() {
+        let args = Args { a: 1, b: 2 };
+        let data = serialize_with(SerializeType::JSON, &args).unwrap();
+        assert_eq!(b"{\"A\":1,\"B\":2}", &data[..]);
+        assert_eq!(args, deserialize_with(SerializeType::JSON, &data).unwrap());
+
+        let data = serialize_with(SerializeType::MsgPack, &args).unwrap();
+        assert_eq!(&[0x92, 1, 2], &data[..]);
+        assert_eq!(
+            args,
+            deserialize_with(SerializeType::MsgPack, &data).unwrap()
+        );
+
+        assert!(serialize_with(SerializeType::SerializeNone, &args).is_err());
+    }
+
+    /// JSON with a trailing newline, good enough to tell it was applied.
+    struct JsonLine;
+
+    impl Serializer for JsonLine {
+        fn serialize(&self, value: &dyn erased_serde::Serialize) -> Result<Vec<u8>> {
+            let mut data = serde_json::to_vec(value)?;
+            data.push(b'\n');
+            Ok(data)
+        }
+        fn deserialize(&self, data: &[u8], visit: DeserializeFn) -> Result<()> {
+            JsonSerializer.deserialize(data, visit)
+        }
+    }
+
+    #[test]
+    fn custom_serializer() {
+        let st = SerializeType::Custom(15);
+        assert!(get_serializer(st).is_none());
+        register_serializer(st, Arc::new(JsonLine)).unwrap();
+
+        let args = Args { a: 1, b: 2 };
+        let data = serialize_with(st, &args).unwrap();
+        assert_eq!(b"{\"A\":1,\"B\":2}\n", &data[..]);
+        assert_eq!(args, deserialize_with(st, &data).unwrap());
+    }
+
+    #[test]
+    fn invalid_ids() {
+        assert!(register_serializer(SerializeType::SerializeNone, Arc::new(JsonLine)).is_err());
+        assert!(register_serializer(SerializeType::Custom(16), Arc::new(JsonLine)).is_err());
+    }
+}
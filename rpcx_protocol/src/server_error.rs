@@ -0,0 +1,152 @@
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use std::{error, fmt};
+
+use crate::{Error, ErrorKind, Metadata, MetadataExt, Result, ERROR_CODE_KEY, ERROR_DETAILS_KEY};
+
+/// an additional, typed piece of information about a `ServerError`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ErrorDetail {
+    /// the name both sides agree on for the type of `value`.
+    #[serde(rename = "type")]
+    pub type_name: String,
+    pub value: serde_json::Value,
+}
+
+/// the error a handler failed with, as the client receives it.
+///
+/// The message is sent under `SERVICE_ERROR` like Go rpcx does, the code
+/// and details under keys of their own, so Go peers still see the message.
+/// Errors of servers which send no code have code 0.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ServerError {
+    pub code: i32,
+    pub message: String,
+    pub details: Vec<ErrorDetail>,
+}
+
+impl ServerError {
+    pub fn new<S: Into<String>>(code: i32, message: S) -> Self {
+        ServerError {
+            code,
+            message: message.into(),
+            details: Vec::new(),
+        }
+    }
+
+    /// Adds `detail`, which the client reads back with `detail`.
+    pub fn add_detail<T: Serialize>(&mut self, type_name: &str, detail: &T) -> Result<()> {
+        let value = serde_json::to_value(detail)
+            .map_err(|err| Error::new(ErrorKind::Serialization, err))?;
+        self.details.push(ErrorDetail {
+            type_name: type_name.to_owned(),
+            value,
+        });
+        Ok(())
+    }
+
+    /// Returns the first detail named `type_name`, `None` if there is none
+    /// or it isn't a `T`.
+    pub fn detail<T: DeserializeOwned>(&self, type_name: &str) -> Option<T> {
+        let detail = self.details.iter().find(|d| d.type_name == type_name)?;
+        serde_json::from_value(detail.value.clone()).ok()
+    }
+
+    pub fn write_to(&self, metadata: &mut Metadata) {
+        metadata.set_error(&self.message);
+        if self.code != 0 {
+            metadata.insert(ERROR_CODE_KEY.to_owned(), self.code.to_string());
+        }
+        if !self.details.is_empty() {
+            if let Ok(details) = serde_json::to_string(&self.details) {
+                metadata.insert(ERROR_DETAILS_KEY.to_owned(), details);
+            }
+        }
+    }
+
+    /// Reads the error of an error reply, malformed codes and details are
+    /// left out.
+    pub fn read_from(metadata: &Metadata) -> Self {
+        ServerError {
+            code: metadata
+                .get(ERROR_CODE_KEY)
+                .and_then(|code| code.parse().ok())
+                .unwrap_or_default(),
+            message: metadata.error().unwrap_or_default().to_owned(),
+            details: metadata
+                .get(ERROR_DETAILS_KEY)
+                .and_then(|details| serde_json::from_str(details).ok())
+                .unwrap_or_default(),
+        }
+    }
+}
+
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl error::Error for ServerError {}
+
+impl From<ServerError> for Error {
+    fn from(err: ServerError) -> Error {
+        Error::new(ErrorKind::Server, err)
+    }
+}
+
+impl Error {
+    /// Returns the error a handler failed with, for errors of replies.
+    pub fn server_error(&self) -> Option<&ServerError> {
+        self.get_ref()?.downcast_ref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SERVICE_ERROR;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct QuotaFailure {
+        subject: String,
+        limit: u32,
+    }
+
+    #[test]
+    fn metadata() {
+        let mut err = ServerError::new(4029, "quota exceeded");
+        let quota = QuotaFailure {
+            subject: "user:42".to_owned(),
+            limit: 100,
+        };
+        err.add_detail("QuotaFailure", &quota).unwrap();
+
+        let mut metadata = Metadata::new();
+        err.write_to(&mut metadata);
+        assert_eq!("quota exceeded", metadata[SERVICE_ERROR]);
+        assert_eq!("4029", metadata[ERROR_CODE_KEY]);
+
+        let back = ServerError::read_from(&metadata);
+        assert_eq!(err, back);
+        assert_eq!(Some(quota), back.detail("QuotaFailure"));
+        assert_eq!(None, back.detail::<QuotaFailure>("RetryInfo"));
+        assert_eq!(None, back.detail::<String>("QuotaFailure"));
+
+        // the errors of Go servers only have a message
+        let mut metadata = Metadata::new();
+        metadata.set_error("rpcx: can't find method Div");
+        let err = ServerError::read_from(&metadata);
+        assert_eq!(0, err.code);
+        assert!(err.details.is_empty());
+    }
+
+    #[test]
+    fn into_error() {
+        let err = Error::from(ServerError::new(7, "permission denied"));
+        assert_eq!(ErrorKind::Server, err.kind());
+        assert_eq!("permission denied", err.to_string());
+        assert_eq!(7, err.server_error().unwrap().code);
+        assert!(Error::from("failed").server_error().is_none());
+    }
+}
@@ -0,0 +1,93 @@
+use std::{
+    cell::RefCell,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
+
+thread_local! {
+    static CURRENT: RefCell<Option<CancellationToken>> = const { RefCell::new(None) };
+}
+
+/// tells a handler that the client canceled its call, or that its deadline
+/// passed.
+///
+/// Handlers are plain functions, so the token of the running call is read
+/// with `CancellationToken::current()`. Long running handlers should check
+/// it now and then and give up once it is canceled, their reply is dropped
+/// anyway.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken {
+    canceled: Arc<AtomicBool>,
+    deadline: Option<Instant>,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Creates a token which is canceled once `deadline` passes.
+    pub fn with_deadline(deadline: Instant) -> Self {
+        CancellationToken {
+            deadline: Some(deadline),
+            ..Default::default()
+        }
+    }
+
+    /// Returns the token of the call handled by this thread, or one which is
+    /// never canceled outside of a handler.
+    pub fn current() -> Self {
+        CURRENT.with(|current| current.borrow().clone().unwrap_or_default())
+    }
+
+    pub fn cancel(&self) {
+        self.canceled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_canceled(&self) -> bool {
+        self.canceled.load(Ordering::SeqCst)
+            || matches!(self.deadline, Some(deadline) if Instant::now() >= deadline)
+    }
+
+    /// Returns the deadline the client set for the call, if any.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// Runs `f` with this token as the current one.
+    pub(crate) fn scope<R>(&self, f: impl FnOnce() -> R) -> R {
+        let previous = CURRENT.with(|current| current.replace(Some(self.clone())));
+        let rt = f();
+        CURRENT.with(|current| current.replace(previous));
+        rt
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn current_token() {
+        assert!(!CancellationToken::current().is_canceled());
+
+        let token = CancellationToken::new();
+        token.cancel();
+        assert!(token.scope(|| CancellationToken::current().is_canceled()));
+        assert!(!CancellationToken::current().is_canceled());
+    }
+
+    #[test]
+    fn deadline() {
+        let now = Instant::now();
+        assert!(CancellationToken::with_deadline(now).is_canceled());
+
+        let token = CancellationToken::with_deadline(now + Duration::from_secs(60));
+        assert!(!token.is_canceled());
+        assert_eq!(Some(now + Duration::from_secs(60)), token.deadline());
+    }
+}
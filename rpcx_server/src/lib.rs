@@ -1,30 +1,62 @@
 use std::{
     boxed::Box,
     collections::HashMap,
-    sync::{Arc, RwLock},
+    sync::{Arc, Mutex, RwLock},
 };
 
 use std::net::SocketAddr;
 
 use rpcx_protocol::*;
 use std::{
-    io::{BufReader, BufWriter, Write},
+    io::{self, BufReader, BufWriter, Write},
     net::{Shutdown, TcpListener, TcpStream},
 };
 
 use std::{
     os::unix::io::{AsRawFd, RawFd},
     thread,
+    time::Instant,
 };
 
 use scoped_threadpool::Pool;
 
+pub mod cancel;
 pub mod plugin;
+pub use cancel::*;
 pub use plugin::*;
 
 pub type RpcxFn = fn(&[u8], SerializeType) -> Result<Vec<u8>>;
+
+/// the options of a `Server` each connection is handled with.
+#[derive(Debug, Clone)]
+struct ConnOpt {
+    compress_threshold: usize,
+    handshake: Handshake,
+    encryption: HashMap<String, Encryption>,
+    fragment_size: usize,
+    max_reassembly_size: usize,
+    decode_limits: DecodeLimits,
+}
+
 pub struct Server {
     pub addr: String,
+    /// replies with payloads shorter than this are sent uncompressed.
+    pub compress_threshold: usize,
+    /// the protocol version and features offered to clients which negotiate.
+    pub handshake: Handshake,
+    /// the encryption of services whose payloads are encrypted, by service
+    /// path. Plain requests to these services are rejected.
+    pub encryption: HashMap<String, Encryption>,
+    /// replies with larger payloads are split into fragments of this size
+    /// for clients which agreed on `FRAGMENT_FEATURE`, 0 never splits them.
+    pub fragment_size: usize,
+    /// the limit of the headers and payloads of incomplete fragmented
+    /// requests of one connection, which are buffered whole until their last
+    /// fragment. It is `DEFAULT_MAX_REASSEMBLY_SIZE` unless raised for larger
+    /// requests, every connection may buffer this much.
+    pub max_reassembly_size: usize,
+    /// the limits requests are decoded, decrypted and decompressed with.
+    pub decode_limits: DecodeLimits,
     raw_fd: Option<RawFd>,
     pub services: Arc<RwLock<HashMap<String, Box<RpcxFn>>>>,
     thread_number: u32,
@@ -41,6 +73,12 @@ impl Server {
         }
         Server {
             addr: s,
+            compress_threshold: 0,
+            handshake: Handshake::supported(),
+            encryption: HashMap::new(),
+            fragment_size: 0,
+            max_reassembly_size: DEFAULT_MAX_REASSEMBLY_SIZE,
+            decode_limits: Default::default(),
             services: Arc::new(RwLock::new(HashMap::new())),
             thread_number,
             register_plugins: Arc::new(RwLock::new(Vec::new())),
@@ -92,8 +130,16 @@ impl Server {
             match stream {
                 Ok(stream) => {
                     let services_cloned = self.services.clone();
+                    let opt = ConnOpt {
+                        compress_threshold: self.compress_threshold,
+                        handshake: self.handshake.clone(),
+                        encryption: self.encryption.clone(),
+                        fragment_size: self.fragment_size,
+                        max_reassembly_size: self.max_reassembly_size,
+                        decode_limits: self.decode_limits,
+                    };
                     thread::spawn(move || {
-                        Server::process(thread_number, services_cloned, stream);
+                        Server::process(thread_number, opt, services_cloned, stream);
                     });
                 }
                 Err(e) => {
@@ -128,19 +174,106 @@ impl Server {
     }
     fn process(
         thread_number: u32,
+        opt: ConnOpt,
         service: Arc<RwLock<HashMap<String, Box<RpcxFn>>>>,
         stream: TcpStream,
     ) {
         let services_cloned = service;
         let local_stream = stream.try_clone().unwrap();
+        let writer = &FrameWriter::new(stream.try_clone().unwrap());
+        let compress_threshold = opt.compress_threshold;
+        let mut reassembler = Reassembler::new(opt.max_reassembly_size);
+        // replies are only split once the client agreed on it
+        let mut fragment_size = 0;
+        // the tokens of the calls being handled, by seq
+        let calls: Mutex<HashMap<u64, CancellationToken>> = Mutex::new(HashMap::new());
+        let calls = &calls;
 
         let mut pool = Pool::new(thread_number);
         pool.scoped(|scoped| {
             let mut reader = BufReader::new(stream.try_clone().unwrap());
             loop {
                 let mut msg = Message::new();
-                match msg.decode(&mut reader) {
-                    Ok(()) => {
+                match msg.read_frame(&mut reader, &opt.decode_limits) {
+                    // the frame was read whole, so only its call fails
+                    Ok(Err(err)) => {
+                        if !msg.is_oneway() && !msg.is_heartbeat() {
+                            let encryption = opt.encryption.get(&msg.service_path);
+                            write_error(writer, &msg, &err, compress_threshold, encryption);
+                        }
+                    }
+                    Ok(Ok(())) => {
+                        // keep what is needed to reject a fragment
+                        let head = if msg.metadata.contains_key(FRAGMENT_KEY) {
+                            Some(Message {
+                                header: msg.header,
+                                service_path: msg.service_path.clone(),
+                                service_method: msg.service_method.clone(),
+                                ..Default::default()
+                            })
+                        } else {
+                            None
+                        };
+                        let mut msg = match reassembler.push(msg) {
+                            Ok(Some(msg)) => msg,
+                            Ok(None) => continue,
+                            Err(err) => {
+                                if let Some(head) = head {
+                                    let encryption = opt.encryption.get(&head.service_path);
+                                    write_error(
+                                        writer,
+                                        &head,
+                                        &err,
+                                        compress_threshold,
+                                        encryption,
+                                    );
+                                }
+                                continue;
+                            }
+                        };
+
+                        if msg.is_heartbeat() {
+                            let mut reply_msg = msg.get_reply().unwrap();
+                            if let Some(offer) = Handshake::read_offer(&msg.metadata) {
+                                let agreed = opt.handshake.negotiate(&offer);
+                                if agreed.has_feature(FRAGMENT_FEATURE) {
+                                    fragment_size = opt.fragment_size;
+                                }
+                                agreed.write_accept(&mut reply_msg.metadata);
+                            }
+                            // the client may close the connection at any time
+                            if let Err(err) = writer.write(&reply_msg) {
+                                eprintln!("failed to write: {}", err);
+                                let _ = local_stream.shutdown(Shutdown::Both);
+                                return;
+                            }
+                            continue;
+                        }
+
+                        if let Some(seq) = msg.metadata.get(CANCEL_KEY) {
+                            let seq = seq.parse::<u64>().ok();
+                            if let Some(token) =
+                                seq.and_then(|seq| calls.lock().unwrap().get(&seq).cloned())
+                            {
+                                token.cancel();
+                            }
+                            continue;
+                        }
+
+                        let encryption = opt.encryption.get(&msg.service_path).cloned();
+                        if let Err(err) =
+                            open_request(encryption.as_ref(), &mut msg, &opt.decode_limits)
+                        {
+                            write_error(
+                                writer,
+                                &msg,
+                                &err,
+                                compress_threshold,
+                                encryption.as_ref(),
+                            );
+                            continue;
+                        }
+
                         let service_path = &msg.service_path;
                         let service_method = &msg.service_method;
                         let key = format!("{}.{}", service_path, service_method);
@@ -148,22 +281,46 @@ impl Server {
                         match map.get(&key) {
                             Some(box_fn) => {
                                 let f = **box_fn;
-                                let local_stream_in_child = local_stream.try_clone().unwrap();
+                                let seq = msg.get_seq();
+                                // the client sends the time left until its deadline,
+                                // one too far away to represent is none
+                                let deadline = msg
+                                    .metadata
+                                    .timeout()
+                                    .and_then(|timeout| Instant::now().checked_add(timeout));
+                                let token = match deadline {
+                                    Some(deadline) => CancellationToken::with_deadline(deadline),
+                                    None => CancellationToken::new(),
+                                };
+                                if !msg.is_oneway() {
+                                    calls.lock().unwrap().insert(seq, token.clone());
+                                }
 
                                 scoped.execute(move || {
-                                    invoke_fn(local_stream_in_child.try_clone().unwrap(), msg, f)
+                                    invoke_fn(
+                                        writer,
+                                        msg,
+                                        f,
+                                        compress_threshold,
+                                        encryption,
+                                        fragment_size,
+                                        token,
+                                    );
+                                    calls.lock().unwrap().remove(&seq);
                                 });
                             }
                             None => {
-                                let err = format!("service {} not found", key);
-                                let reply_msg = msg.get_reply().unwrap();
-                                let mut metadata = reply_msg.metadata.borrow_mut();
-                                (*metadata).insert(SERVICE_ERROR.to_string(), err);
-                                drop(metadata);
-                                let data = reply_msg.encode();
-                                let mut writer = BufWriter::new(local_stream.try_clone().unwrap());
-                                writer.write_all(&data).unwrap();
-                                writer.flush().unwrap();
+                                let err = Error::new(
+                                    ErrorKind::NotFound,
+                                    format!("service {} not found", key),
+                                );
+                                write_error(
+                                    writer,
+                                    &msg,
+                                    &err,
+                                    compress_threshold,
+                                    encryption.as_ref(),
+                                );
                             }
                         }
                     }
@@ -189,20 +346,107 @@ impl Server {
     }
 }
 
-fn invoke_fn(stream: TcpStream, msg: Message, f: RpcxFn) {
+/// writes whole frames to a connection, one at a time, so the frames of
+/// replies written by concurrent handlers don't interleave.
+struct FrameWriter {
+    writer: Mutex<BufWriter<TcpStream>>,
+}
+
+impl FrameWriter {
+    fn new(stream: TcpStream) -> Self {
+        FrameWriter {
+            writer: Mutex::new(BufWriter::new(stream)),
+        }
+    }
+
+    /// Writes and flushes the frame of `msg`.
+    fn write(&self, msg: &Message) -> io::Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        // large payloads bypass the buffer of the writer
+        msg.encode_vectored().write_to(&mut *writer)?;
+        writer.flush()
+    }
+}
+
+fn invoke_fn(
+    writer: &FrameWriter,
+    msg: Message,
+    f: RpcxFn,
+    compress_threshold: usize,
+    encryption: Option<Encryption>,
+    fragment_size: usize,
+    token: CancellationToken,
+) {
+    // the client doesn't wait for the replies of canceled calls
+    if token.is_canceled() {
+        return;
+    }
     let mut reply_msg = msg.get_reply().unwrap();
-    let reply = f(&msg.payload, msg.get_serialize_type().unwrap()).unwrap();
+    let reply = token.scope(|| f(&msg.payload, msg.get_serialize_type().unwrap()));
+    if token.is_canceled() {
+        return;
+    }
+    let reply = match reply {
+        Ok(reply) => reply,
+        Err(err) => {
+            write_error(writer, &msg, &err, compress_threshold, encryption.as_ref());
+            return;
+        }
+    };
     reply_msg.payload = reply;
-    let data = reply_msg.encode();
+    reply_msg.apply_compress_threshold(compress_threshold);
+    if let Some(ref encryption) = encryption {
+        if let Err(err) = encryption.seal(&mut reply_msg) {
+            write_error(writer, &msg, &err, compress_threshold, Some(encryption));
+            return;
+        }
+    }
+
+    // fragments of other replies may go between these
+    for fragment in split(reply_msg, fragment_size) {
+        if writer.write(&fragment).is_err() {
+            break;
+        }
+    }
+}
 
-    let mut writer = BufWriter::new(stream.try_clone().unwrap());
-    match writer.write_all(&data) {
-        Ok(()) => {}
-        Err(_err) => {}
+/// Decrypts a request to a service with encryption, rejecting plain ones.
+fn open_request(
+    encryption: Option<&Encryption>,
+    msg: &mut Message,
+    limits: &DecodeLimits,
+) -> Result<()> {
+    match encryption {
+        Some(encryption) => encryption.open(msg, limits),
+        None if msg.is_encrypted() => Err(Error::new(
+            ErrorKind::Protocol,
+            "service doesn't accept encrypted requests",
+        )),
+        None => Ok(()),
     }
-    match writer.flush() {
-        Ok(()) => {}
-        Err(_err) => {}
+}
+
+/// Replies to `msg` with an error, sealed for services with encryption so
+/// clients can trust its kind, code and details.
+fn write_error(
+    writer: &FrameWriter,
+    msg: &Message,
+    err: &Error,
+    compress_threshold: usize,
+    encryption: Option<&Encryption>,
+) {
+    let mut reply_msg = msg.get_reply().unwrap();
+    reply_msg.apply_compress_threshold(compress_threshold);
+    reply_msg.set_reply_error(err);
+    if let Some(encryption) = encryption {
+        // without a key the error is sent plain, and clients only trust its
+        // message
+        if encryption.seal(&mut reply_msg).is_err() {
+            reply_msg.metadata.remove(ENCRYPTION_KEY_ID);
+        }
+    }
+    if let Err(err) = writer.write(&reply_msg) {
+        eprintln!("failed to write: {}", err);
     }
 }
 
@@ -224,3 +468,24 @@ macro_rules! register_func {
         );
     }};
 }
+
+/// Registers a handler like `register_func!` whose function returns a
+/// `Result`. Its errors are sent to the client, `ServerError`s with their
+/// code and details.
+#[macro_export]
+macro_rules! register_try_func {
+    ($rpc_server:expr, $service_path:expr, $service_method:expr, $service_fn:expr, $meta:expr, $arg_type:ty, $reply_type:ty) => {{
+        let f: RpcxFn = |x, st| {
+            let mut args: $arg_type = Default::default();
+            args.from_slice(st, x)?;
+            let reply: $reply_type = $service_fn(args)?;
+            reply.into_bytes(st)
+        };
+        $rpc_server.register_fn(
+            $service_path.to_string(),
+            $service_method.to_string(),
+            $meta,
+            f,
+        );
+    }};
+}
@@ -0,0 +1,26 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use bytes::BytesMut;
+use rpcx_protocol::{DecodeLimits, ErrorKind, RpcxCodec};
+use tokio_util::codec::Decoder;
+
+fuzz_target!(|data: &[u8]| {
+    let limits = DecodeLimits {
+        max_frame_size: 1024 * 1024,
+        max_metadata_size: 64 * 1024,
+        max_payload_size: 1024 * 1024,
+    };
+    let mut codec = RpcxCodec::with_limits(limits);
+    let mut buf = BytesMut::from(data);
+    loop {
+        match codec.decode(&mut buf) {
+            Ok(Some(_)) => continue,
+            Ok(None) => break,
+            Err(err) => {
+                assert_eq!(ErrorKind::Protocol, err.kind());
+                break;
+            }
+        }
+    }
+});
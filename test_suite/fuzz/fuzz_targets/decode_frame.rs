@@ -0,0 +1,22 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use bytes::Bytes;
+use rpcx_protocol::{ErrorKind, Frame, RpcxMessage};
+
+fuzz_target!(|data: &[u8]| {
+    if data.len() < 12 {
+        return;
+    }
+    let mut header = [0u8; 12];
+    header.copy_from_slice(&data[..12]);
+    match Frame::from_parts(header, Bytes::copy_from_slice(&data[12..])) {
+        Ok(frame) => {
+            // lazily parsed parts must not panic either
+            let _ = frame.metadata();
+            let _ = frame.get_error();
+            let _ = frame.to_message();
+        }
+        Err(err) => assert_eq!(ErrorKind::Protocol, err.kind()),
+    }
+});
@@ -0,0 +1,13 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use rpcx_protocol::{ErrorKind, Message, RpcxMessage};
+
+fuzz_target!(|data: &[u8]| {
+    let mut msg = Message::new();
+    let mut reader = data;
+    if let Err(err) = msg.decode(&mut reader) {
+        // a short input is an io error, everything else must be a protocol error
+        assert!(err.kind() == ErrorKind::Protocol || err.kind() == ErrorKind::IO);
+    }
+});
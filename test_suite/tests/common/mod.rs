@@ -0,0 +1,56 @@
+//! the servers and clients shared by the tests.
+#![allow(dead_code)]
+
+use mul_model::{ArithAddArgs, ArithAddReply};
+use rpcx::*;
+
+use std::{
+    net::{SocketAddr, TcpListener},
+    thread,
+};
+
+pub fn mul(args: ArithAddArgs) -> ArithAddReply {
+    ArithAddReply { c: args.a * args.b }
+}
+
+/// Creates a server for a free port of localhost with `threads` threads for
+/// the handlers.
+pub fn server(threads: u32) -> Server {
+    Server::new("127.0.0.1:0".to_owned(), threads)
+}
+
+/// Creates a server like `server` which serves `mul` as Arith.Mul.
+pub fn mul_server(threads: u32) -> Server {
+    let mut rpc_server = server(threads);
+    register_func!(
+        rpc_server,
+        "Arith",
+        "Mul",
+        mul,
+        "".to_owned(),
+        ArithAddArgs,
+        ArithAddReply
+    );
+    rpc_server
+}
+
+/// Starts `rpc_server` in the background and returns its address.
+pub fn start_server(rpc_server: Server) -> SocketAddr {
+    let listener = TcpListener::bind(&rpc_server.addr).unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || rpc_server.start_with_listener(listener));
+    addr
+}
+
+/// Connects a client with the default options to `addr`.
+pub fn client(addr: SocketAddr) -> Client {
+    client_with(addr, Opt::default())
+}
+
+/// Connects a client with `opt` to `addr`.
+pub fn client_with(addr: SocketAddr, opt: Opt) -> Client {
+    let mut c = Client::new(&addr.to_string());
+    c.opt = opt;
+    c.start().unwrap();
+    c
+}
@@ -0,0 +1,9 @@
+module github.com/smallnest/rpcx-rs/test_suite/golden
+
+go 1.18
+
+require (
+	github.com/golang/snappy v0.0.4
+	github.com/smallnest/rpcx v1.8.0
+	google.golang.org/protobuf v1.28.1
+)
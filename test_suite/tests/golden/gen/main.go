@@ -0,0 +1,172 @@
+// Command gen writes the golden frames of test_golden.rs, encoded by Go rpcx.
+//
+// Run it in this directory, it rewrites the .hex files of the parent one:
+//
+//	go mod tidy
+//	go run . ..
+package main
+
+import (
+	"bytes"
+	"encoding/hex"
+	"fmt"
+	"io"
+	"log"
+	"os"
+	"path/filepath"
+	"strings"
+
+	"github.com/golang/snappy"
+	"github.com/smallnest/rpcx/protocol"
+	"github.com/smallnest/rpcx/share"
+	"google.golang.org/protobuf/encoding/protowire"
+)
+
+// Args and Reply are the types of mul_model.
+type Args struct {
+	A int
+	B int
+}
+
+type Reply struct {
+	C int
+}
+
+// snappyCompress is the id rpcx-rs registers its snappy compressor for.
+const snappyCompress protocol.CompressType = 2
+
+// snappyCompressor writes the snappy framing format with a buffered writer,
+// like the snappy compressor of Go rpcx does.
+type snappyCompressor struct{}
+
+func (snappyCompressor) Zip(data []byte) ([]byte, error) {
+	var b bytes.Buffer
+	w := snappy.NewBufferedWriter(&b)
+	if _, err := w.Write(data); err != nil {
+		return nil, err
+	}
+	if err := w.Close(); err != nil {
+		return nil, err
+	}
+	return b.Bytes(), nil
+}
+
+func (snappyCompressor) Unzip(data []byte) ([]byte, error) {
+	return io.ReadAll(snappy.NewReader(bytes.NewReader(data)))
+}
+
+type golden struct {
+	name    string
+	comment string
+	msg     *protocol.Message
+}
+
+func main() {
+	protocol.Compressors[snappyCompress] = snappyCompressor{}
+
+	dir := ".."
+	if len(os.Args) > 1 {
+		dir = os.Args[1]
+	}
+
+	for _, g := range corpus() {
+		if err := write(filepath.Join(dir, g.name+".hex"), g.comment, g.msg.Encode()); err != nil {
+			log.Fatal(err)
+		}
+	}
+}
+
+func corpus() []golden {
+	args := &Args{A: 10, B: 20}
+
+	heartbeat := message(protocol.Request, protocol.MsgPack, 1, "", "", nil)
+	heartbeat.SetHeartbeat(true)
+	heartbeatReply := message(protocol.Response, protocol.MsgPack, 1, "", "", nil)
+	heartbeatReply.SetHeartbeat(true)
+
+	oneway := message(protocol.Request, protocol.JSON, 2, "Arith", "Mul", encode(protocol.JSON, args))
+	oneway.SetOneway(true)
+
+	gzip := message(protocol.Request, protocol.JSON, 3, "Arith", "Mul", encode(protocol.JSON, args))
+	gzip.SetCompressType(protocol.Gzip)
+	gzipReply := message(protocol.Response, protocol.JSON, 3, "Arith", "Mul", encode(protocol.JSON, &Reply{C: 200}))
+	gzipReply.SetCompressType(protocol.Gzip)
+
+	snappyReq := message(protocol.Request, protocol.JSON, 8, "Arith", "Mul", encode(protocol.JSON, args))
+	snappyReq.SetCompressType(snappyCompress)
+
+	errorReply := message(protocol.Response, protocol.JSON, 4, "Arith", "Div", nil)
+	errorReply.SetMessageStatusType(protocol.Error)
+	errorReply.Metadata = map[string]string{
+		protocol.ServiceError: "rpcx: can't find method Div",
+	}
+
+	// protobuf needs generated types, ProtoArgs{A: 10, B: 20} is written
+	// with the wire helpers of the protobuf module instead
+	var pb []byte
+	pb = protowire.AppendTag(pb, 1, protowire.VarintType)
+	pb = protowire.AppendVarint(pb, 10)
+	pb = protowire.AppendTag(pb, 2, protowire.VarintType)
+	pb = protowire.AppendVarint(pb, 20)
+
+	withMetadata := message(protocol.Request, protocol.JSON, 7, "Arith", "Mul", encode(protocol.JSON, args))
+	withMetadata.Metadata = map[string]string{
+		"__ID":        "6ba7b810-9dad-11d1-80b4-00c04fd430c9",
+		share.AuthKey: "bearer tGzv3JOkF0XG5Qx2TlKWIA",
+		"traceparent": "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01",
+	}
+
+	return []golden{
+		{"heartbeat_request", "a client heartbeat, msgpack, seq 1", heartbeat},
+		{"heartbeat_response", "the server echoing a heartbeat as a response", heartbeatReply},
+		{"oneway_request", "a oneway JSON call of Arith.Mul", oneway},
+		{"gzip_request", "a JSON call of Arith.Mul with a gzip payload", gzip},
+		{"gzip_response", `the gzip reply {"C":200}`, gzipReply},
+		{"error_response", "an error reply for an unknown method", errorReply},
+		{"msgpack_request", "a msgpack call, structs are maps like vmihailenco/msgpack writes them",
+			message(protocol.Request, protocol.MsgPack, 5, "Arith", "Mul", encode(protocol.MsgPack, args))},
+		{"protobuf_request", "a protobuf call with ProtoArgs{A: 10, B: 20}",
+			message(protocol.Request, protocol.ProtoBuffer, 6, "Arith", "Mul", pb)},
+		{"metadata_request", "a JSON call with several metadata keys", withMetadata},
+		{"snappy_request", "a JSON call of Arith.Mul with a snappy payload", snappyReq},
+	}
+}
+
+func message(mt protocol.MessageType, st protocol.SerializeType, seq uint64, path, method string, payload []byte) *protocol.Message {
+	msg := protocol.NewMessage()
+	msg.SetMessageType(mt)
+	msg.SetSerializeType(st)
+	msg.SetSeq(seq)
+	msg.ServicePath = path
+	msg.ServiceMethod = method
+	msg.Payload = payload
+	return msg
+}
+
+func encode(st protocol.SerializeType, v interface{}) []byte {
+	data, err := share.Codecs[st].Encode(v)
+	if err != nil {
+		log.Fatal(err)
+	}
+	return data
+}
+
+// write stores data as lines of 16 hex bytes after a comment.
+func write(path, comment string, data []byte) error {
+	var b strings.Builder
+	fmt.Fprintf(&b, "# %s\n", comment)
+	for len(data) > 0 {
+		n := 16
+		if len(data) < n {
+			n = len(data)
+		}
+		line := make([]string, n)
+		for i := range line {
+			line[i] = hex.EncodeToString(data[i : i+1])
+		}
+		b.WriteString(strings.Join(line, " "))
+		b.WriteString("\n")
+		data = data[n:]
+	}
+	return os.WriteFile(path, []byte(b.String()), 0o644)
+}
@@ -0,0 +1,53 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use crate::common::{mul_server, start_server};
+    use mul_model::{ArithAddArgs, ArithAddReply};
+    use rpcx::*;
+
+    use std::collections::HashMap;
+
+    #[tokio::test]
+    async fn test_async_client() {
+        let addr = start_server(mul_server(2)).to_string();
+        let mut c = AsyncClient::connect(&addr, Opt::default()).await.unwrap();
+        c.handshake(&Handshake::supported()).await.unwrap();
+
+        let metadata = HashMap::new();
+        let args = ArithAddArgs { a: 6, b: 7 };
+        let reply: ArithAddReply = c
+            .call("Arith", "Mul", false, &metadata, &args)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(42, reply.c);
+
+        // calls don't borrow the client, so they can run as tasks
+        let tasks: Vec<_> = (1..=10)
+            .map(|a| {
+                let args = ArithAddArgs { a, b: 10 };
+                tokio::spawn(c.call::<ArithAddReply>("Arith", "Mul", false, &metadata, &args))
+            })
+            .collect();
+        for (a, task) in (1..=10).zip(tasks) {
+            let reply = task.await.unwrap().unwrap().unwrap();
+            assert_eq!(a * 10, reply.c);
+        }
+        assert_eq!(0, c.pending_calls());
+    }
+
+    #[tokio::test]
+    async fn test_blocking_client_in_runtime() {
+        let addr = start_server(mul_server(2)).to_string();
+        let mut c = Client::new(&addr);
+        c.start().unwrap();
+
+        let args = ArithAddArgs { a: 6, b: 7 };
+        let reply: ArithAddReply = c
+            .call("Arith", "Mul", false, &HashMap::new(), &args)
+            .unwrap()
+            .unwrap();
+        assert_eq!(42, reply.c);
+    }
+}
@@ -0,0 +1,85 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use crate::common::{server, start_server};
+    use mul_model::{ArithAddArgs, ArithAddReply};
+    use rpcx::*;
+
+    use std::{collections::HashMap, thread, time::Duration};
+
+    fn slow_mul(args: ArithAddArgs) -> ArithAddReply {
+        thread::sleep(Duration::from_millis(200));
+        ArithAddReply { c: args.a * args.b }
+    }
+
+    fn slow_server() -> String {
+        let mut rpc_server = server(4);
+        register_func!(
+            rpc_server,
+            "Arith",
+            "Mul",
+            slow_mul,
+            "".to_owned(),
+            ArithAddArgs,
+            ArithAddReply
+        );
+        start_server(rpc_server).to_string()
+    }
+
+    #[tokio::test]
+    async fn test_backpressure_fail() {
+        let addr = slow_server();
+        let opt = Opt {
+            max_pending_calls: 1,
+            backpressure: Backpressure::Fail,
+            ..Default::default()
+        };
+        let c = AsyncClient::connect(&addr, opt).await.unwrap();
+
+        let metadata = HashMap::new();
+        let args = ArithAddArgs { a: 6, b: 7 };
+        let first = c.call::<ArithAddReply>("Arith", "Mul", false, &metadata, &args);
+        let err = c
+            .call::<ArithAddReply>("Arith", "Mul", false, &metadata, &args)
+            .await
+            .unwrap()
+            .unwrap_err();
+        assert_eq!(ErrorKind::ResourceExhausted, err.kind());
+        assert!(err.is_retryable());
+
+        assert_eq!(42, first.await.unwrap().unwrap().c);
+        let reply = c
+            .call::<ArithAddReply>("Arith", "Mul", false, &metadata, &args)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(42, reply.c);
+    }
+
+    #[tokio::test]
+    async fn test_backpressure_wait() {
+        let addr = slow_server();
+        let opt = Opt {
+            max_pending_calls: 1,
+            ..Default::default()
+        };
+        let c = AsyncClient::connect(&addr, opt).await.unwrap();
+
+        let metadata = HashMap::new();
+        let tasks: Vec<_> = (1..=3)
+            .map(|a| {
+                let args = ArithAddArgs { a, b: 10 };
+                tokio::spawn(c.call::<ArithAddReply>("Arith", "Mul", false, &metadata, &args))
+            })
+            .collect();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(1, c.pending_calls());
+
+        for (a, task) in (1..=3).zip(tasks) {
+            let reply = task.await.unwrap().unwrap().unwrap();
+            assert_eq!(a * 10, reply.c);
+        }
+        assert_eq!(0, c.pending_calls());
+    }
+}
@@ -0,0 +1,96 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use crate::common::{client, mul_server, start_server};
+    use mul_model::{ArithAddArgs, ArithAddReply};
+    use rpcx::*;
+
+    use std::{
+        collections::HashMap,
+        thread,
+        time::{Duration, Instant},
+    };
+
+    // waits until the deadline of the call passes, for at most five seconds
+    fn slow_mul(args: ArithAddArgs) -> ArithAddReply {
+        let token = CancellationToken::current();
+        let start = Instant::now();
+        while !token.is_canceled() && start.elapsed() < Duration::from_secs(5) {
+            thread::sleep(Duration::from_millis(10));
+        }
+        ArithAddReply { c: args.a * args.b }
+    }
+
+    #[test]
+    fn test_call_options() {
+        // one thread for the handlers
+        let mut rpc_server = mul_server(1);
+        register_func!(
+            rpc_server,
+            "Arith",
+            "SlowMul",
+            slow_mul,
+            "".to_owned(),
+            ArithAddArgs,
+            ArithAddReply
+        );
+        let mut c = client(start_server(rpc_server));
+
+        let metadata = HashMap::new();
+        let args = ArithAddArgs { a: 6, b: 7 };
+        let start = Instant::now();
+        let options = CallOptions::timeout(Duration::from_millis(200));
+        let err = c
+            .call_with_options::<ArithAddReply>(
+                "Arith", "SlowMul", false, &metadata, &args, &options,
+            )
+            .unwrap()
+            .unwrap_err();
+        assert_eq!(ErrorKind::Timeout, err.kind());
+        assert!(start.elapsed() < Duration::from_secs(2));
+        assert_eq!(0, c.pending_calls());
+
+        // the server gave up on the call as well, so the next one isn't
+        // stuck behind it
+        let reply: ArithAddReply = c
+            .call("Arith", "Mul", false, &metadata, &args)
+            .unwrap()
+            .unwrap();
+        assert_eq!(42, reply.c);
+        assert!(start.elapsed() < Duration::from_secs(2));
+
+        let options = CallOptions {
+            serialize_type: Some(SerializeType::MsgPack),
+            compress_type: Some(CompressType::Gzip),
+            ..Default::default()
+        };
+        let reply: ArithAddReply = c
+            .call_with_options("Arith", "Mul", false, &metadata, &args, &options)
+            .unwrap()
+            .unwrap();
+        assert_eq!(42, reply.c);
+
+        // deadlines too far away to represent are none, on both sides
+        let options = CallOptions::timeout(Duration::new(u64::MAX, 0));
+        let reply: ArithAddReply = c
+            .call_with_options("Arith", "Mul", false, &metadata, &args, &options)
+            .unwrap()
+            .unwrap();
+        assert_eq!(42, reply.c);
+        let mut far = HashMap::new();
+        far.insert(SERVER_TIMEOUT.to_owned(), u64::MAX.to_string());
+        let reply: ArithAddReply = c.call("Arith", "Mul", false, &far, &args).unwrap().unwrap();
+        assert_eq!(42, reply.c);
+
+        let options = CallOptions {
+            deadline: Some(Instant::now()),
+            ..Default::default()
+        };
+        let err = c
+            .call_with_options::<ArithAddReply>("Arith", "Mul", false, &metadata, &args, &options)
+            .unwrap()
+            .unwrap_err();
+        assert_eq!(ErrorKind::Timeout, err.kind());
+    }
+}
@@ -0,0 +1,68 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use crate::common::{client_with, server, start_server};
+    use mul_model::{ArithAddArgs, ArithAddReply};
+    use rpcx::*;
+
+    use std::{
+        collections::HashMap,
+        sync::atomic::{AtomicBool, Ordering},
+        thread,
+        time::{Duration, Instant},
+    };
+
+    static CANCELED: AtomicBool = AtomicBool::new(false);
+
+    // waits until the call is canceled, for at most five seconds
+    fn slow_mul(args: ArithAddArgs) -> ArithAddReply {
+        let token = CancellationToken::current();
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_secs(5) {
+            if token.is_canceled() {
+                CANCELED.store(true, Ordering::SeqCst);
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        ArithAddReply { c: args.a * args.b }
+    }
+
+    #[test]
+    fn test_cancel() {
+        let mut rpc_server = server(2);
+        register_func!(
+            rpc_server,
+            "Arith",
+            "Mul",
+            slow_mul,
+            "".to_owned(),
+            ArithAddArgs,
+            ArithAddReply
+        );
+        let addr = start_server(rpc_server);
+
+        let opt = Opt {
+            notify_cancel: true,
+            ..Default::default()
+        };
+        let mut c = client_with(addr, opt);
+        let agreed = c.handshake(&Handshake::supported()).unwrap();
+        assert!(agreed.has_feature(CANCEL_FEATURE));
+
+        // dropping the future forgets the call and stops the handler
+        let args = ArithAddArgs { a: 6, b: 7 };
+        let call = c.send::<ArithAddReply>("Arith", "Mul", false, false, &HashMap::new(), &args);
+        assert_eq!(1, c.pending_calls());
+        thread::sleep(Duration::from_millis(100));
+        drop(call);
+        assert_eq!(0, c.pending_calls());
+
+        let start = Instant::now();
+        while !CANCELED.load(Ordering::SeqCst) && start.elapsed() < Duration::from_secs(3) {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(CANCELED.load(Ordering::SeqCst));
+    }
+}
@@ -0,0 +1,78 @@
+#[cfg(test)]
+mod tests {
+    use mul_model::{ArithAddArgs, ArithAddReply};
+    use rpcx::*;
+
+    use std::{
+        collections::HashMap,
+        io::{BufReader, Write},
+        net::TcpListener,
+        thread,
+    };
+
+    // a server whose reply to a = 2 is corrupted on the way
+    fn start_corrupting_server() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            let (conn, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(conn.try_clone().unwrap());
+            let mut writer = conn;
+            loop {
+                let mut msg = Message::new();
+                if msg.decode(&mut reader).is_err() {
+                    return;
+                }
+                let mut reply = msg.get_reply().unwrap();
+                let mut corrupt = false;
+                if !msg.is_heartbeat() {
+                    let st = msg.get_serialize_type().unwrap();
+                    let mut args = ArithAddArgs::default();
+                    args.from_slice(st, &msg.payload).unwrap();
+                    let c = ArithAddReply { c: args.a * args.b };
+                    reply.payload = c.into_bytes(st).unwrap();
+                    reply.set_checksum(true);
+                    corrupt = args.a == 2;
+                }
+                let mut data = reply.encode();
+                if corrupt {
+                    *data.last_mut().unwrap() ^= 1;
+                }
+                writer.write_all(&data).unwrap();
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_checksum_mismatch() {
+        let addr = start_corrupting_server();
+        let c = AsyncClient::connect(&addr, Opt::default()).await.unwrap();
+
+        let metadata = HashMap::new();
+        let calls: Vec<_> = (1..=4)
+            .map(|a| {
+                let args = ArithAddArgs { a, b: 10 };
+                c.call::<ArithAddReply>("Arith", "Mul", false, &metadata, &args)
+            })
+            .collect();
+        for (a, call) in (1..=4).zip(calls) {
+            let reply = call.await.unwrap();
+            if a == 2 {
+                assert_eq!(ErrorKind::Protocol, reply.unwrap_err().kind());
+            } else {
+                assert_eq!(a * 10, reply.unwrap().c);
+            }
+        }
+
+        // the connection survives the corrupt reply
+        assert_eq!(ConnectionState::Ready, c.state());
+        let args = ArithAddArgs { a: 6, b: 7 };
+        let reply = c
+            .call::<ArithAddReply>("Arith", "Mul", false, &metadata, &args)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(42, reply.c);
+    }
+}
@@ -0,0 +1,153 @@
+#[cfg(test)]
+mod tests {
+    use bytes::{Bytes, BytesMut};
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+    use rpcx::*;
+    use tokio_util::codec::Decoder;
+
+    // a JSON request for Arith.Add with one metadata pair
+    const MSG_DATA: [u8; 114] = [
+        8, 0, 0, 16, 0, 0, 0, 0, 73, 150, 2, 210, 0, 0, 0, 98, 0, 0, 0, 5, 65, 114, 105, 116, 104,
+        0, 0, 0, 3, 65, 100, 100, 0, 0, 0, 48, 0, 0, 0, 4, 95, 95, 73, 68, 0, 0, 0, 36, 54, 98, 97,
+        55, 98, 56, 49, 48, 45, 57, 100, 97, 100, 45, 49, 49, 100, 49, 45, 56, 48, 98, 52, 45, 48,
+        48, 99, 48, 52, 102, 100, 52, 51, 48, 99, 57, 0, 0, 0, 26, 123, 10, 9, 9, 34, 65, 34, 58,
+        32, 49, 44, 10, 9, 9, 34, 66, 34, 58, 32, 50, 44, 10, 9, 125, 10, 9,
+    ];
+
+    fn mutate(rng: &mut StdRng, data: &[u8]) -> Vec<u8> {
+        let mut data = data.to_vec();
+        for _ in 0..rng.gen_range(1..8) {
+            match rng.gen_range(0..4) {
+                0 if !data.is_empty() => {
+                    let i = rng.gen_range(0..data.len());
+                    data[i] = rng.gen();
+                }
+                1 if !data.is_empty() => {
+                    let len = rng.gen_range(0..data.len());
+                    data.truncate(len);
+                }
+                2 => {
+                    let i = rng.gen_range(0..=data.len());
+                    data.insert(i, rng.gen());
+                }
+                _ => {
+                    // overwrite a length prefix with something large
+                    if data.len() >= 20 {
+                        let i = rng.gen_range(0..data.len() - 4);
+                        data[i..i + 4].copy_from_slice(&rng.gen::<u32>().to_be_bytes());
+                    }
+                }
+            }
+        }
+        data
+    }
+
+    fn check_all_decoders(data: &[u8]) {
+        let mut msg = Message::new();
+        let mut reader = data;
+        if let Err(err) = msg.decode(&mut reader) {
+            // running out of input is an io error, everything else is a protocol error
+            assert!(
+                err.kind() == ErrorKind::Protocol || err.kind() == ErrorKind::IO,
+                "{:?}",
+                err
+            );
+        }
+
+        if data.len() >= 12 {
+            let mut header = [0u8; 12];
+            header.copy_from_slice(&data[..12]);
+            match Frame::from_parts(header, Bytes::copy_from_slice(&data[12..])) {
+                Ok(frame) => {
+                    let _ = frame.metadata();
+                    let _ = frame.get_error();
+                    let _ = frame.to_message();
+                }
+                Err(err) => assert_eq!(ErrorKind::Protocol, err.kind()),
+            }
+        }
+
+        let limits = DecodeLimits {
+            max_frame_size: 1024 * 1024,
+            max_metadata_size: 64 * 1024,
+            max_payload_size: 1024 * 1024,
+        };
+        let mut codec = RpcxCodec::with_limits(limits);
+        let mut buf = BytesMut::from(data);
+        loop {
+            match codec.decode(&mut buf) {
+                Ok(Some(_)) => continue,
+                Ok(None) => break,
+                Err(err) => {
+                    assert_eq!(ErrorKind::Protocol, err.kind());
+                    break;
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn decode_mutated_frames() {
+        let mut rng = StdRng::seed_from_u64(0x7270_6378);
+        for _ in 0..20_000 {
+            let data = mutate(&mut rng, &MSG_DATA);
+            check_all_decoders(&data);
+        }
+    }
+
+    #[test]
+    fn decode_random_bytes() {
+        let mut rng = StdRng::seed_from_u64(42);
+        for _ in 0..20_000 {
+            let len = rng.gen_range(0..256);
+            let mut data: Vec<u8> = (0..len).map(|_| rng.gen()).collect();
+            if !data.is_empty() && rng.gen_bool(0.5) {
+                data[0] = 0x08;
+            }
+            check_all_decoders(&data);
+        }
+    }
+
+    #[test]
+    fn reject_unknown_compress_type() {
+        let mut data = MSG_DATA.to_vec();
+        data[2] |= 0x1C;
+
+        let mut msg = Message::new();
+        let err = msg.decode(&mut &data[..]).unwrap_err();
+        assert_eq!(ErrorKind::Protocol, err.kind());
+    }
+
+    #[test]
+    fn reject_invalid_utf8() {
+        let mut data = MSG_DATA.to_vec();
+        // first byte of the service path
+        data[20] = 0xFF;
+
+        let mut msg = Message::new();
+        let err = msg.decode(&mut &data[..]).unwrap_err();
+        assert_eq!(ErrorKind::Protocol, err.kind());
+    }
+
+    #[test]
+    fn reject_payload_over_limit() {
+        let limits = DecodeLimits {
+            max_payload_size: 16,
+            ..Default::default()
+        };
+        let mut msg = Message::new();
+        let err = msg
+            .decode_with_limits(&mut &MSG_DATA[..], &limits)
+            .unwrap_err();
+        assert_eq!(ErrorKind::Protocol, err.kind());
+
+        let limits = DecodeLimits {
+            max_metadata_size: 16,
+            ..Default::default()
+        };
+        let err = msg
+            .decode_with_limits(&mut &MSG_DATA[..], &limits)
+            .unwrap_err();
+        assert_eq!(ErrorKind::Protocol, err.kind());
+    }
+}
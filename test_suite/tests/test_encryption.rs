@@ -0,0 +1,54 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use crate::common::{client, client_with, mul_server, start_server};
+    use mul_model::{ArithAddArgs, ArithAddReply};
+    use rpcx::*;
+
+    use std::{collections::HashMap, sync::Arc};
+
+    fn encryption() -> Encryption {
+        let key = EncryptionKey::new("k1", Cipher::ChaCha20Poly1305, [42u8; 32]);
+        Encryption::new(Arc::new(StaticKeyProvider::new(key)))
+    }
+
+    #[test]
+    fn test_encryption() {
+        let mut rpc_server = mul_server(1);
+        rpc_server
+            .encryption
+            .insert("Arith".to_owned(), encryption());
+        let addr = start_server(rpc_server);
+
+        let args = ArithAddArgs { a: 6, b: 7 };
+
+        // a client with the key
+        let mut opt = Opt {
+            compress_type: CompressType::Gzip,
+            ..Default::default()
+        };
+        opt.encryption.insert("Arith".to_owned(), encryption());
+        let mut c = client_with(addr, opt);
+        let reply: ArithAddReply = c
+            .call("Arith", "Mul", false, &HashMap::new(), &args)
+            .unwrap()
+            .unwrap();
+        assert_eq!(42, reply.c);
+
+        // errors are sealed as well, so their kind is trusted
+        let err = c
+            .call::<ArithAddReply>("Arith", "Div", false, &HashMap::new(), &args)
+            .unwrap()
+            .unwrap_err();
+        assert_eq!(ErrorKind::NotFound, err.kind());
+
+        // and one sending plain requests
+        let mut c = client(addr);
+        let err = c
+            .call::<ArithAddReply>("Arith", "Mul", false, &HashMap::new(), &args)
+            .unwrap()
+            .unwrap_err();
+        assert_eq!(ErrorKind::Other, err.kind());
+    }
+}
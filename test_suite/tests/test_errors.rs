@@ -0,0 +1,74 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use crate::common::{client, mul_server, server, start_server};
+    use mul_model::{ArithAddArgs, ArithAddReply};
+    use rpcx::*;
+
+    use std::collections::HashMap;
+
+    fn div(args: ArithAddArgs) -> Result<ArithAddReply> {
+        if args.b == 0 {
+            let mut err = ServerError::new(1001, "division by zero");
+            err.add_detail("Dividend", &args.a)?;
+            return Err(err.into());
+        }
+        Ok(ArithAddReply { c: args.a / args.b })
+    }
+
+    #[test]
+    fn test_not_found() {
+        let mut c = client(start_server(mul_server(1)));
+
+        let args = ArithAddArgs { a: 6, b: 7 };
+        let err = c
+            .call::<ArithAddReply>("Arith", "Div", false, &HashMap::new(), &args)
+            .unwrap()
+            .unwrap_err();
+        assert_eq!(ErrorKind::NotFound, err.kind());
+        assert!(!err.is_retryable());
+        assert_eq!("service Arith.Div not found", err.to_string());
+        assert_eq!(0, err.server_error().unwrap().code);
+
+        let reply: ArithAddReply = c
+            .call("Arith", "Mul", false, &HashMap::new(), &args)
+            .unwrap()
+            .unwrap();
+        assert_eq!(42, reply.c);
+    }
+
+    #[test]
+    fn test_server_error() {
+        let mut rpc_server = server(1);
+        register_try_func!(
+            rpc_server,
+            "Arith",
+            "Div",
+            div,
+            "".to_owned(),
+            ArithAddArgs,
+            ArithAddReply
+        );
+        let mut c = client(start_server(rpc_server));
+
+        let args = ArithAddArgs { a: 42, b: 0 };
+        let err = c
+            .call::<ArithAddReply>("Arith", "Div", false, &HashMap::new(), &args)
+            .unwrap()
+            .unwrap_err();
+        assert_eq!(ErrorKind::Server, err.kind());
+        assert_eq!("division by zero", err.to_string());
+        let server_error = err.server_error().unwrap();
+        assert_eq!(1001, server_error.code);
+        assert_eq!(Some(42), server_error.detail::<u64>("Dividend"));
+
+        // the handler's thread survives the error
+        let args = ArithAddArgs { a: 42, b: 6 };
+        let reply: ArithAddReply = c
+            .call("Arith", "Div", false, &HashMap::new(), &args)
+            .unwrap()
+            .unwrap();
+        assert_eq!(7, reply.c);
+    }
+}
@@ -0,0 +1,95 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use crate::common::{client_with, mul_server, server, start_server};
+    use mul_model::{ArithAddArgs, ArithAddReply};
+    use rpcx::*;
+
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_fragment() {
+        let mut rpc_server = mul_server(1);
+        rpc_server.fragment_size = 4;
+        // the header of a request is charged at least 128 bytes
+        rpc_server.max_reassembly_size = 160;
+        let addr = start_server(rpc_server);
+
+        let opt = Opt {
+            fragment_size: 4,
+            ..Default::default()
+        };
+        let mut c = client_with(addr, opt);
+        let agreed = c.handshake(&Handshake::supported()).unwrap();
+        assert!(agreed.has_feature(FRAGMENT_FEATURE));
+
+        // both the request and the reply are split
+        let args = ArithAddArgs { a: 6, b: 7 };
+        let reply: ArithAddReply = c
+            .call("Arith", "Mul", false, &HashMap::new(), &args)
+            .unwrap()
+            .unwrap();
+        assert_eq!(42, reply.c);
+
+        // requests beyond the server's limit are rejected
+        let args = ArithAddArgs {
+            a: 123456789,
+            b: 987654321,
+        };
+        let reply: Result<ArithAddReply> = c
+            .call("Arith", "Mul", false, &HashMap::new(), &args)
+            .unwrap();
+        assert!(reply.is_err());
+
+        // and the connection is still usable
+        let args = ArithAddArgs { a: 3, b: 4 };
+        let reply: ArithAddReply = c
+            .call("Arith", "Mul", false, &HashMap::new(), &args)
+            .unwrap()
+            .unwrap();
+        assert_eq!(12, reply.c);
+    }
+
+    // replies with a megabyte of the byte `a`
+    fn export(data: &[u8], st: SerializeType) -> Result<Vec<u8>> {
+        let mut args = ArithAddArgs::default();
+        args.from_slice(st, data)?;
+        Ok(vec![args.a as u8; 1 << 20])
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_replies() {
+        let mut rpc_server = server(4);
+        rpc_server.fragment_size = 64 << 10;
+        rpc_server.register_fn(
+            "Batch".to_owned(),
+            "Export".to_owned(),
+            "".to_owned(),
+            export,
+        );
+        let addr = start_server(rpc_server).to_string();
+
+        // all the replies may be incomplete at once
+        let opt = Opt {
+            max_reassembly_size: 32 << 20,
+            ..Default::default()
+        };
+        let mut c = AsyncClient::connect(&addr, opt).await.unwrap();
+        c.handshake(&Handshake::supported()).await.unwrap();
+
+        // the fragments of the replies of all handlers share the connection
+        let calls: Vec<_> = (1..=16u64)
+            .map(|a| {
+                let args = ArithAddArgs { a, b: 0 };
+                let call = c.send_raw("Batch", "Export", false, false, &HashMap::new(), &args);
+                tokio::spawn(call)
+            })
+            .collect();
+        for (a, call) in (1..=16u8).zip(calls) {
+            let reply = call.await.unwrap().unwrap();
+            assert_eq!(1 << 20, reply.data.len());
+            assert!(reply.data.iter().all(|&b| b == a));
+        }
+    }
+}
@@ -0,0 +1,382 @@
+#[cfg(test)]
+mod tests {
+    use bytes::{Bytes, BytesMut};
+    use mul_model::{ArithAddArgs, ArithAddReply};
+    use rpcx::*;
+    use tokio_util::codec::Decoder;
+
+    use std::collections::HashMap;
+
+    /// a frame of the corpus and the message it holds.
+    struct Golden {
+        name: &'static str,
+        hex: &'static str,
+        message_type: MessageType,
+        heartbeat: bool,
+        oneway: bool,
+        compress_type: CompressType,
+        status: MessageStatusType,
+        serialize_type: SerializeType,
+        seq: u64,
+        service_path: &'static str,
+        service_method: &'static str,
+        metadata: &'static [(&'static str, &'static str)],
+        /// the payload after decompression
+        payload: &'static [u8],
+    }
+
+    const ARGS_JSON: &[u8] = b"{\"A\":10,\"B\":20}";
+
+    const CORPUS: &[Golden] = &[
+        Golden {
+            name: "heartbeat_request",
+            hex: include_str!("golden/heartbeat_request.hex"),
+            message_type: MessageType::Request,
+            heartbeat: true,
+            oneway: false,
+            compress_type: CompressType::CompressNone,
+            status: MessageStatusType::Normal,
+            serialize_type: SerializeType::MsgPack,
+            seq: 1,
+            service_path: "",
+            service_method: "",
+            metadata: &[],
+            payload: b"",
+        },
+        Golden {
+            name: "heartbeat_response",
+            hex: include_str!("golden/heartbeat_response.hex"),
+            message_type: MessageType::Response,
+            heartbeat: true,
+            oneway: false,
+            compress_type: CompressType::CompressNone,
+            status: MessageStatusType::Normal,
+            serialize_type: SerializeType::MsgPack,
+            seq: 1,
+            service_path: "",
+            service_method: "",
+            metadata: &[],
+            payload: b"",
+        },
+        Golden {
+            name: "oneway_request",
+            hex: include_str!("golden/oneway_request.hex"),
+            message_type: MessageType::Request,
+            heartbeat: false,
+            oneway: true,
+            compress_type: CompressType::CompressNone,
+            status: MessageStatusType::Normal,
+            serialize_type: SerializeType::JSON,
+            seq: 2,
+            service_path: "Arith",
+            service_method: "Mul",
+            metadata: &[],
+            payload: ARGS_JSON,
+        },
+        Golden {
+            name: "gzip_request",
+            hex: include_str!("golden/gzip_request.hex"),
+            message_type: MessageType::Request,
+            heartbeat: false,
+            oneway: false,
+            compress_type: CompressType::Gzip,
+            status: MessageStatusType::Normal,
+            serialize_type: SerializeType::JSON,
+            seq: 3,
+            service_path: "Arith",
+            service_method: "Mul",
+            metadata: &[],
+            payload: ARGS_JSON,
+        },
+        Golden {
+            name: "gzip_response",
+            hex: include_str!("golden/gzip_response.hex"),
+            message_type: MessageType::Response,
+            heartbeat: false,
+            oneway: false,
+            compress_type: CompressType::Gzip,
+            status: MessageStatusType::Normal,
+            serialize_type: SerializeType::JSON,
+            seq: 3,
+            service_path: "Arith",
+            service_method: "Mul",
+            metadata: &[],
+            payload: b"{\"C\":200}",
+        },
+        Golden {
+            name: "error_response",
+            hex: include_str!("golden/error_response.hex"),
+            message_type: MessageType::Response,
+            heartbeat: false,
+            oneway: false,
+            compress_type: CompressType::CompressNone,
+            status: MessageStatusType::Error,
+            serialize_type: SerializeType::JSON,
+            seq: 4,
+            service_path: "Arith",
+            service_method: "Div",
+            metadata: &[(SERVICE_ERROR, "rpcx: can't find method Div")],
+            payload: b"",
+        },
+        Golden {
+            name: "msgpack_request",
+            hex: include_str!("golden/msgpack_request.hex"),
+            message_type: MessageType::Request,
+            heartbeat: false,
+            oneway: false,
+            compress_type: CompressType::CompressNone,
+            status: MessageStatusType::Normal,
+            serialize_type: SerializeType::MsgPack,
+            seq: 5,
+            service_path: "Arith",
+            service_method: "Mul",
+            metadata: &[],
+            payload: &[0x82, 0xa1, 0x41, 0x0a, 0xa1, 0x42, 0x14],
+        },
+        Golden {
+            name: "protobuf_request",
+            hex: include_str!("golden/protobuf_request.hex"),
+            message_type: MessageType::Request,
+            heartbeat: false,
+            oneway: false,
+            compress_type: CompressType::CompressNone,
+            status: MessageStatusType::Normal,
+            serialize_type: SerializeType::Protobuf,
+            seq: 6,
+            service_path: "Arith",
+            service_method: "Mul",
+            metadata: &[],
+            payload: &[0x08, 0x0a, 0x10, 0x14],
+        },
+        Golden {
+            name: "metadata_request",
+            hex: include_str!("golden/metadata_request.hex"),
+            message_type: MessageType::Request,
+            heartbeat: false,
+            oneway: false,
+            compress_type: CompressType::CompressNone,
+            status: MessageStatusType::Normal,
+            serialize_type: SerializeType::JSON,
+            seq: 7,
+            service_path: "Arith",
+            service_method: "Mul",
+            metadata: &[
+                (REQUEST_ID, "6ba7b810-9dad-11d1-80b4-00c04fd430c9"),
+                (AUTH_KEY, "bearer tGzv3JOkF0XG5Qx2TlKWIA"),
+                (
+                    TRACEPARENT,
+                    "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01",
+                ),
+            ],
+            payload: ARGS_JSON,
+        },
+        Golden {
+            name: "snappy_request",
+            hex: include_str!("golden/snappy_request.hex"),
+            message_type: MessageType::Request,
+            heartbeat: false,
+            oneway: false,
+            compress_type: CompressType::Snappy,
+            status: MessageStatusType::Normal,
+            serialize_type: SerializeType::JSON,
+            seq: 8,
+            service_path: "Arith",
+            service_method: "Mul",
+            metadata: &[],
+            payload: ARGS_JSON,
+        },
+    ];
+
+    fn parse_hex(s: &str) -> Vec<u8> {
+        s.lines()
+            .filter(|line| !line.starts_with('#'))
+            .flat_map(|line| line.split_whitespace())
+            .map(|b| u8::from_str_radix(b, 16).unwrap())
+            .collect()
+    }
+
+    impl Golden {
+        fn data(&self) -> Vec<u8> {
+            parse_hex(self.hex)
+        }
+
+        fn metadata(&self) -> Metadata {
+            self.metadata
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect()
+        }
+
+        fn message(&self) -> Message {
+            MessageBuilder::new()
+                .message_type(self.message_type)
+                .heartbeat(self.heartbeat)
+                .oneway(self.oneway)
+                .compress_type(self.compress_type)
+                .message_status_type(self.status)
+                .serialize_type(self.serialize_type)
+                .seq(self.seq)
+                .service_path(self.service_path)
+                .service_method(self.service_method)
+                .metadata(self.metadata())
+                .payload(self.payload.to_vec())
+                .build()
+                .unwrap()
+        }
+
+        fn check<M: RpcxMessage>(&self, msg: &M) {
+            assert!(msg.check_magic_number(), "{}", self.name);
+            assert_eq!(0, msg.get_version(), "{}", self.name);
+            assert_eq!(
+                Some(self.message_type),
+                msg.get_message_type(),
+                "{}",
+                self.name
+            );
+            assert_eq!(self.heartbeat, msg.is_heartbeat(), "{}", self.name);
+            assert_eq!(self.oneway, msg.is_oneway(), "{}", self.name);
+            assert_eq!(
+                Some(self.compress_type),
+                msg.get_compress_type(),
+                "{}",
+                self.name
+            );
+            assert_eq!(
+                Some(self.status),
+                msg.get_message_status_type(),
+                "{}",
+                self.name
+            );
+            assert_eq!(
+                Some(self.serialize_type),
+                msg.get_serialize_type(),
+                "{}",
+                self.name
+            );
+            assert_eq!(self.seq, msg.get_seq(), "{}", self.name);
+        }
+
+        fn check_message(&self, msg: &Message) {
+            self.check(msg);
+            assert_eq!(self.service_path, msg.service_path, "{}", self.name);
+            assert_eq!(self.service_method, msg.service_method, "{}", self.name);
+            assert_eq!(self.metadata(), msg.metadata, "{}", self.name);
+            assert_eq!(self.payload, &msg.payload[..], "{}", self.name);
+        }
+
+        /// Checks that `data` encodes the same message as the golden frame.
+        /// Frames are compared byte by byte unless metadata order or the
+        /// compressor may differ.
+        fn check_encoded(&self, data: &[u8]) {
+            let golden = self.data();
+            if self.metadata.len() <= 1 && self.compress_type == CompressType::CompressNone {
+                assert_eq!(golden, data, "{}", self.name);
+                return;
+            }
+
+            let (expected, actual) = (split(&golden), split(data));
+            assert_eq!(expected.header, actual.header, "{}", self.name);
+            assert_eq!(expected.service_path, actual.service_path, "{}", self.name);
+            assert_eq!(
+                expected.service_method, actual.service_method,
+                "{}",
+                self.name
+            );
+            assert_eq!(
+                expected.raw_metadata().len(),
+                actual.raw_metadata().len(),
+                "{}",
+                self.name
+            );
+            assert_eq!(
+                expected.metadata().unwrap(),
+                actual.metadata().unwrap(),
+                "{}",
+                self.name
+            );
+            assert_eq!(
+                expected.decompressed_payload().unwrap(),
+                actual.decompressed_payload().unwrap(),
+                "{}",
+                self.name
+            );
+        }
+    }
+
+    fn split(data: &[u8]) -> Frame {
+        let mut header = [0u8; 12];
+        header.copy_from_slice(&data[..12]);
+        Frame::from_parts(header, Bytes::copy_from_slice(&data[16..])).unwrap()
+    }
+
+    #[test]
+    fn decode_golden_frames() {
+        for golden in CORPUS {
+            let data = golden.data();
+
+            let mut msg = Message::new();
+            msg.decode(&mut &data[..]).unwrap();
+            golden.check_message(&msg);
+
+            let mut frame = Frame::new();
+            frame.decode(&mut &data[..]).unwrap();
+            golden.check(&frame);
+            assert_eq!(golden.payload, &frame.decompressed_payload().unwrap()[..]);
+            assert_eq!(golden.metadata(), frame.metadata().unwrap());
+        }
+    }
+
+    #[test]
+    fn decode_golden_stream() {
+        let mut buf = BytesMut::new();
+        for golden in CORPUS {
+            buf.extend_from_slice(&golden.data());
+        }
+
+        let mut codec = RpcxCodec::new();
+        for golden in CORPUS {
+            let msg = codec.decode(&mut buf).unwrap().unwrap();
+            golden.check_message(&msg);
+        }
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn encode_golden_frames() {
+        for golden in CORPUS {
+            golden.check_encoded(&golden.message().encode());
+
+            // and decoding then encoding again changes nothing
+            let mut msg = Message::new();
+            msg.decode(&mut &golden.data()[..]).unwrap();
+            golden.check_encoded(&msg.encode());
+
+            let mut frame = Frame::new();
+            frame.decode(&mut &golden.data()[..]).unwrap();
+            assert_eq!(golden.data(), frame.encode(), "{}", golden.name);
+        }
+    }
+
+    #[test]
+    fn golden_payloads() {
+        let by_name: HashMap<_, _> = CORPUS.iter().map(|g| (g.name, g)).collect();
+
+        let args: ArithAddArgs =
+            deserialize_with(SerializeType::JSON, by_name["gzip_request"].payload).unwrap();
+        assert_eq!((10, 20), (args.a, args.b));
+        let reply: ArithAddReply =
+            deserialize_with(SerializeType::JSON, by_name["gzip_response"].payload).unwrap();
+        assert_eq!(200, reply.c);
+
+        // Go encodes structs as msgpack maps, rmp-serde reads them as well
+        let args: ArithAddArgs =
+            deserialize_with(SerializeType::MsgPack, by_name["msgpack_request"].payload).unwrap();
+        assert_eq!((10, 20), (args.a, args.b));
+
+        let msg = by_name["error_response"].message();
+        assert_eq!(
+            Some("rpcx: can't find method Div".to_owned()),
+            msg.get_error()
+        );
+    }
+}
@@ -0,0 +1,37 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use crate::common::{client, mul_server, start_server};
+    use mul_model::{ArithAddArgs, ArithAddReply};
+    use rpcx::*;
+
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_handshake() {
+        let mut rpc_server = mul_server(1);
+        rpc_server.handshake = Handshake::new(
+            PROTOCOL_VERSION,
+            vec!["fragment".to_owned(), "crc32c".to_owned()],
+        );
+        let mut c = client(start_server(rpc_server));
+        assert_eq!(&Handshake::default(), c.protocol());
+
+        let offer = Handshake::new(
+            PROTOCOL_VERSION,
+            vec!["crc32c".to_owned(), "encrypt".to_owned()],
+        );
+        let agreed = c.handshake(&offer).unwrap().clone();
+        assert_eq!(PROTOCOL_VERSION, agreed.version);
+        assert_eq!(vec!["crc32c".to_owned()], agreed.features);
+
+        // calls still work after the handshake
+        let args = ArithAddArgs { a: 6, b: 7 };
+        let reply: ArithAddReply = c
+            .call("Arith", "Mul", false, &HashMap::new(), &args)
+            .unwrap()
+            .unwrap();
+        assert_eq!(42, reply.c);
+    }
+}
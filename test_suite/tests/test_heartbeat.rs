@@ -0,0 +1,82 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use crate::common::{mul_server, start_server};
+    use mul_model::{ArithAddArgs, ArithAddReply};
+    use rpcx::*;
+
+    use std::{
+        collections::HashMap,
+        io::Read,
+        net::TcpListener,
+        thread,
+        time::{Duration, Instant},
+    };
+
+    fn heartbeat_opt() -> Opt {
+        Opt {
+            reconnect: false,
+            heartbeat_interval: Duration::from_millis(50),
+            heartbeat_timeout: Duration::from_millis(200),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_heartbeat_answered() {
+        let addr = start_server(mul_server(1)).to_string();
+
+        let c = AsyncClient::connect(&addr, heartbeat_opt()).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert_eq!(ConnectionState::Ready, c.state());
+
+        // a timeout of 0 waits for the replies forever
+        let opt = Opt {
+            heartbeat_timeout: Duration::from_millis(0),
+            ..heartbeat_opt()
+        };
+        let c2 = AsyncClient::connect(&addr, opt).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert_eq!(ConnectionState::Ready, c2.state());
+
+        let args = ArithAddArgs { a: 6, b: 7 };
+        let reply = c
+            .call::<ArithAddReply>("Arith", "Mul", false, &HashMap::new(), &args)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(42, reply.c);
+    }
+
+    #[tokio::test]
+    async fn test_heartbeat_unanswered() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+
+        // a peer which reads requests but never replies, like the far end of
+        // a half-open connection
+        thread::spawn(move || {
+            let (mut conn, _) = listener.accept().unwrap();
+            let mut buf = [0u8; 1024];
+            while let Ok(n) = conn.read(&mut buf) {
+                if n == 0 {
+                    break;
+                }
+            }
+        });
+
+        let c = AsyncClient::connect(&addr, heartbeat_opt()).await.unwrap();
+        let start = Instant::now();
+        let args = ArithAddArgs { a: 6, b: 7 };
+        let err = c
+            .call::<ArithAddReply>("Arith", "Mul", false, &HashMap::new(), &args)
+            .await
+            .unwrap()
+            .unwrap_err();
+        assert_eq!(ErrorKind::Network, err.kind());
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert_eq!(ConnectionState::Closed, c.state());
+        assert_eq!(0, c.pending_calls());
+    }
+}
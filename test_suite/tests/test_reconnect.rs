@@ -0,0 +1,116 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use crate::common::mul_server;
+    use mul_model::{ArithAddArgs, ArithAddReply};
+    use rpcx::*;
+
+    use std::{
+        collections::HashMap,
+        io::Read,
+        net::TcpListener,
+        thread,
+        time::{Duration, Instant},
+    };
+
+    #[tokio::test]
+    async fn test_reconnect() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+
+        // the first connection is dropped after the first request, then a
+        // server takes over the listener
+        thread::spawn(move || {
+            let (mut conn, _) = listener.accept().unwrap();
+            let mut buf = [0u8; 16];
+            conn.read_exact(&mut buf).unwrap();
+            drop(conn);
+            mul_server(1).start_with_listener(listener)
+        });
+
+        let mut opt = Opt::default();
+        opt.backoff.initial = Duration::from_millis(20);
+        opt.backoff.jitter = 0.0;
+        let c = AsyncClient::connect(&addr, opt).await.unwrap();
+        assert_eq!(ConnectionState::Ready, c.state());
+
+        let metadata = HashMap::new();
+        let args = ArithAddArgs { a: 6, b: 7 };
+        let err = c
+            .call::<ArithAddReply>("Arith", "Mul", false, &metadata, &args)
+            .await
+            .unwrap()
+            .unwrap_err();
+        assert_eq!(ErrorKind::Network, err.kind());
+        assert_eq!(0, c.pending_calls());
+
+        let start = Instant::now();
+        while c.state() != ConnectionState::Ready && start.elapsed() < Duration::from_secs(5) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let reply = c
+            .call::<ArithAddReply>("Arith", "Mul", false, &metadata, &args)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(42, reply.c);
+
+        c.close();
+        assert_eq!(ConnectionState::Closed, c.state());
+        let err = c
+            .call::<ArithAddReply>("Arith", "Mul", false, &metadata, &args)
+            .await
+            .unwrap()
+            .unwrap_err();
+        assert_eq!(ErrorKind::Network, err.kind());
+    }
+
+    #[tokio::test]
+    async fn test_calls_while_disconnecting() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+
+        // the first connection is dropped while calls are sent, then a
+        // server takes over the listener
+        thread::spawn(move || {
+            let (mut conn, _) = listener.accept().unwrap();
+            let mut buf = [0u8; 1024];
+            let start = Instant::now();
+            while start.elapsed() < Duration::from_millis(100) {
+                if let Ok(0) | Err(_) = conn.read(&mut buf) {
+                    break;
+                }
+            }
+            drop(conn);
+            mul_server(1).start_with_listener(listener)
+        });
+
+        let mut opt = Opt::default();
+        opt.backoff.initial = Duration::from_millis(5);
+        opt.backoff.jitter = 0.0;
+        let c = AsyncClient::connect(&addr, opt).await.unwrap();
+
+        let metadata = HashMap::new();
+        let args = ArithAddArgs { a: 6, b: 7 };
+        let mut tasks = Vec::new();
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_millis(300) {
+            let call = c.call::<ArithAddReply>("Arith", "Mul", false, &metadata, &args);
+            tasks.push(tokio::spawn(tokio::time::timeout(
+                Duration::from_secs(5),
+                call,
+            )));
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+
+        // every call either got its reply or failed with the connection
+        for task in tasks {
+            match task.await.unwrap().expect("call hangs").unwrap() {
+                Ok(reply) => assert_eq!(42, reply.c),
+                Err(err) => assert_eq!(ErrorKind::Network, err.kind()),
+            }
+        }
+        assert_eq!(0, c.pending_calls());
+    }
+}
@@ -1,5 +1,8 @@
+mod common;
+
 #[cfg(test)]
 mod tests {
+    use crate::common::mul;
     use mul_model::{ArithAddArgs, ArithAddReply};
     use rpcx::*;
 
@@ -14,10 +17,6 @@ mod tests {
         ArithAddReply { c: args.a + args.b }
     }
 
-    fn mul(args: ArithAddArgs) -> ArithAddReply {
-        ArithAddReply { c: args.a * args.b }
-    }
-
     #[test]
     fn test_xclient_and_server() {
         // setup server